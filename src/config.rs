@@ -0,0 +1,744 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+use chrono::{FixedOffset, NaiveDate, NaiveTime, Utc};
+use reqwest::header::HeaderValue;
+use serde_json::{Map, Value};
+use teloxide::types::{Message, UserId};
+
+use crate::{guard::InjectionGuard, state::ChatSettings};
+
+const DEFAULT_GREETING: &str = "Hi! I'm a tiny LLM running on a Raspberry Pi Zero 2 W. Ask me anything with /qwen followed by your question.";
+
+const DEFAULT_QUEUE_MESSAGE: &str =
+    "The Pi is busy, you're number {position} in the queue. The answer will come as soon as it's your turn.";
+
+const DEFAULT_WELCOME_NOTICE: &str = "Welcome! Just so you know: I run on a Raspberry Pi Zero 2 W, so answers take a while and only a few requests are answered at a time. If it's busy you'll be put in a queue.";
+
+const DEFAULT_RANDOM_PROMPTS: &[&str] = &[
+    "Tell me a short joke.",
+    "Tell me a fun fact about space.",
+    "Tell me a fun fact about animals.",
+    "Write a haiku about a Raspberry Pi.",
+    "Give me a random word and its meaning.",
+];
+
+const DEFAULT_SERVER_OFFLINE_MESSAGE: &str =
+    "The model server is offline right now, try again later.";
+
+const DEFAULT_EMPTY_ANSWER_MESSAGE: &str =
+    "The model had nothing to say, try rephrasing the question.";
+
+/// Settings read from the environment (see `.env.example`).
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Telegram user ids that are allowed to run admin-only commands.
+    pub admin_ids: Vec<UserId>,
+    /// Ask the model to answer in the language the prompt was written in.
+    pub detect_language: bool,
+    /// How many previous prompt/response pairs are sent along with `/qwen`, 0 disables history.
+    pub history_turns: usize,
+    /// Whether chats remember previous messages at all, unless they turn it on or off with `/memory`.
+    pub history_enabled: bool,
+    /// Upper limit for the per-chat `/memory` override.
+    pub max_history_turns: usize,
+    /// Frames the placeholder message cycles through while waiting for the model, empty disables it.
+    pub placeholder_frames: Vec<String>,
+    /// How long each placeholder frame is shown. Telegram rate limits edits, so don't go too low.
+    pub placeholder_interval: Duration,
+    /// Sent (together with the command list) when the bot is added to a group.
+    pub greeting: String,
+    /// Prompts `/random` picks from.
+    pub random_prompts: Vec<String>,
+    /// Extra fields merged into every completion request, e.g. `{"min_p": 0.1, "mirostat": 2}`.
+    pub extra_params: Map<String, Value>,
+    /// Replaces the body of chat requests, with `{{prompt}}`, `{{model}}`, `{{messages}}` and
+    /// `{{max_tokens}}` filled in. Empty uses the built-in body.
+    pub request_template: Map<String, Value>,
+    /// How long to wait for the model to load at startup before starting anyway.
+    pub startup_timeout: Duration,
+    /// Delay between the parts of a message that's too long to send at once.
+    pub chunk_delay: Duration,
+    /// Answer commands posted in channels the bot is an admin of, they're ignored otherwise.
+    pub channel_replies: bool,
+    /// Answer messages forwarded to the bot in a private chat as if they were sent with `/qwen`.
+    pub forwarded_prompts: bool,
+    /// How many model requests can run at once, the rest wait for their turn.
+    pub max_concurrent_requests: usize,
+    /// `max_tokens` sent with every request, so a looping model can't keep the Pi busy forever.
+    pub max_tokens: u32,
+    /// Upper limit for the per-chat `/maxtokens` override.
+    pub max_tokens_limit: u32,
+    /// Largest file in bytes the bot downloads, bigger ones are refused before using up the Pi's memory.
+    pub max_file_size: u32,
+    /// Log the full prompts and answers, when off only their length and a hash are logged.
+    pub log_prompts: bool,
+    /// Ask the server what its slots were doing when a request fails, shown next to the error in `/errors`.
+    pub error_diagnostics: bool,
+    /// Stop the running generations on `/pause` instead of letting them finish.
+    pub pause_aborts_requests: bool,
+    /// Sent when a request has to wait for a free slot, `{position}` is replaced with its place in
+    /// the queue. `None` waits silently.
+    pub queue_message: Option<String>,
+    /// Sent instead of the answer when the model answers with nothing but whitespace.
+    pub empty_answer_message: String,
+    /// Sent instead of the answer when the server can't be connected to at all.
+    pub server_offline_message: String,
+    /// Message the admins when a request finds the server offline, at most every few minutes.
+    pub offline_alerts: bool,
+    /// How long the bot has to be idle before `unload_command` runs, `None` never unloads.
+    pub idle_unload_after: Option<Duration>,
+    /// Shell command that unloads the model, e.g. stopping the llama.cpp service.
+    pub unload_command: Option<String>,
+    /// Shell command that loads the model again before the next request.
+    pub wake_command: Option<String>,
+    /// Proxy every request to the server goes through, whatever its scheme. `HTTP_PROXY` and
+    /// `HTTPS_PROXY` are left to reqwest, which uses each for its own scheme.
+    pub proxy: Option<String>,
+    /// How many times a request is retried when the server answers with a 5xx error.
+    pub server_retries: u32,
+    /// Add a "retrying..." note to the placeholder while a failed request is retried.
+    pub show_retries: bool,
+    /// How often the server is pinged to keep the pooled connection open, `None` doesn't ping.
+    pub keep_alive_interval: Option<Duration>,
+    /// `max_tokens` of new requests while the server is busy, `None` never lowers it.
+    pub load_max_tokens: Option<u32>,
+    /// The server counts as busy with this many idle slots or fewer.
+    pub load_idle_slots: u32,
+    /// How often the health check is polled to see whether the server is busy.
+    pub load_poll_interval: Duration,
+    /// Answers are cut off when a line or sentence repeats more often than this in a row, 0 never cuts.
+    pub repetition_limit: usize,
+    /// Sent as bearer tokens to the server, the next one is used while a key is rate limited.
+    /// Empty sends no `Authorization` header.
+    pub api_keys: Vec<String>,
+    /// How long a rate limited API key is skipped when the server doesn't say how long to wait.
+    pub api_key_cooldown: Duration,
+    /// Model sent in requests when a chat didn't pick one with `/model`.
+    pub model: String,
+    /// Path of the chat completions endpoint on the server, for proxies that move it.
+    pub chat_path: String,
+    /// Path of the server's health check, polled at startup and by `/health`.
+    pub health_path: String,
+    /// Sampling parameters per model id, replacing the defaults when a chat uses that model.
+    pub model_profiles: Map<String, Value>,
+    /// Friendly names for model ids, e.g. `small` for `qwen2-0_5b-instruct-q4_k_m`.
+    pub model_aliases: Vec<(String, String)>,
+    /// Extra names for commands, e.g. `ask` for `qwen`, so `/ask` works like `/qwen`.
+    pub command_aliases: Vec<(String, String)>,
+    /// How many model requests everyone but the admins can make per day, `None` for no limit.
+    pub daily_request_limit: Option<u32>,
+    /// Time zone whose midnight starts a new day for the daily limit.
+    pub daily_limit_offset: FixedOffset,
+    /// Where the daily request count is kept, so restarting doesn't reset it.
+    pub daily_limit_file: String,
+    /// Sent once to every user the first time they use the bot, `None` sends nothing.
+    pub welcome_notice: Option<String>,
+    /// Where the users who got the welcome notice are kept, so restarting doesn't send it again.
+    pub welcomed_file: String,
+    /// Daily start and end times during which only admins can use the model, e.g. for backups.
+    /// A window can go past midnight.
+    pub maintenance_windows: Vec<(NaiveTime, NaiveTime)>,
+    /// Time zone of the maintenance windows.
+    pub maintenance_offset: FixedOffset,
+    /// Telegram file id of a sticker sent along with the `/start` greeting.
+    pub welcome_sticker: Option<String>,
+    /// Telegram file id or URL of a photo sent along with the `/start` greeting.
+    pub welcome_photo: Option<String>,
+    /// Speech endpoint of the TTS server used by `/say`, which is disabled without one.
+    pub tts_url: Option<String>,
+    /// Model asked from the TTS server, the OpenAI API requires one.
+    pub tts_model: String,
+    /// Voice asked from the TTS server, its default when `None`.
+    pub tts_voice: Option<String>,
+    /// Named system prompts picked with `/persona`, sorted by name.
+    pub personas: Vec<(String, String)>,
+    /// Put a short quote of the prompt above answers in groups.
+    pub quote_prompts: bool,
+    /// Show link previews for URLs in messages, off by default because the model's links clutter chats.
+    pub link_previews: bool,
+    /// What happens to prompts that try to override the system prompt, e.g. on a persona bot.
+    pub injection_guard: InjectionGuard,
+    /// React to `/health` with an emoji for the status, on top of the text reply.
+    pub health_reactions: bool,
+    /// How long `/qwen` may generate before the partial answer is sent, 0 means no limit.
+    pub time_budget_secs: u64,
+}
+
+// Only read at startup, changing them with /reload does nothing until the bot restarts
+const RESTART_ONLY: &[&str] = &[
+    "max_concurrent_requests",
+    "startup_timeout",
+    "idle_unload_after",
+    "unload_command",
+    "proxy",
+    "keep_alive_interval",
+    "load_idle_slots",
+    "load_poll_interval",
+];
+
+impl Config {
+    /// Reads the config from the environment. Instead of stopping at the first invalid value,
+    /// every problem is collected, so a headless Pi can be fixed in one go.
+    pub fn from_env() -> Result<Self, Vec<String>> {
+        let mut env = Env::default();
+        let admin_ids = std::env::var("ADMIN_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .filter_map(|id| match id.parse::<u64>() {
+                Ok(id) => Some(UserId(id)),
+                Err(_) => {
+                    env.problems
+                        .push(format!("ADMIN_IDS: invalid user id {:?}", id));
+                    None
+                }
+            })
+            .collect();
+
+        let config = Self {
+            admin_ids,
+            detect_language: env.flag("DETECT_LANGUAGE"),
+            history_turns: env.parse("HISTORY_TURNS", 0),
+            history_enabled: env.flag_or("HISTORY_ENABLED", true),
+            max_history_turns: env.parse("MAX_HISTORY_TURNS", 10),
+            placeholder_frames: std::env::var("PLACEHOLDER_FRAMES")
+                .unwrap_or_else(|_| "thinking.,thinking..,thinking...".to_string())
+                .split(',')
+                .map(str::trim)
+                .filter(|frame| !frame.is_empty())
+                .map(String::from)
+                .collect(),
+            placeholder_interval: Duration::from_millis(env.parse("PLACEHOLDER_INTERVAL_MS", 1500)),
+            greeting: std::env::var("GREETING").unwrap_or_else(|_| DEFAULT_GREETING.to_string()),
+            random_prompts: env.json_strings("RANDOM_PROMPTS").unwrap_or_else(|| {
+                DEFAULT_RANDOM_PROMPTS
+                    .iter()
+                    .map(|prompt| prompt.to_string())
+                    .collect()
+            }),
+            extra_params: env.json_object("EXTRA_PARAMS"),
+            request_template: env.json_object("REQUEST_TEMPLATE"),
+            startup_timeout: Duration::from_secs(env.parse("STARTUP_TIMEOUT_SECS", 300)),
+            chunk_delay: Duration::from_millis(env.parse("CHUNK_DELAY_MS", 300)),
+            channel_replies: env.flag("CHANNEL_REPLIES"),
+            forwarded_prompts: env.flag("FORWARDED_PROMPTS"),
+            max_concurrent_requests: env.parse("MAX_CONCURRENT_REQUESTS", 1),
+            max_tokens: env.parse("MAX_TOKENS", 256),
+            max_tokens_limit: env.parse("MAX_TOKENS_LIMIT", 1024),
+            max_file_size: env
+                .parse::<u32>("MAX_FILE_SIZE_KB", 256)
+                .saturating_mul(1024),
+            log_prompts: env.flag_or("LOG_PROMPTS", true),
+            error_diagnostics: env.flag("ERROR_DIAGNOSTICS"),
+            pause_aborts_requests: env.flag("PAUSE_ABORTS_REQUESTS"),
+            queue_message: match std::env::var("QUEUE_MESSAGE") {
+                Ok(message) if message.trim().is_empty() => None,
+                Ok(message) => Some(message),
+                Err(_) => Some(DEFAULT_QUEUE_MESSAGE.to_string()),
+            },
+            empty_answer_message: env
+                .non_empty("EMPTY_ANSWER_MESSAGE")
+                .unwrap_or_else(|| DEFAULT_EMPTY_ANSWER_MESSAGE.to_string()),
+            server_offline_message: env
+                .non_empty("SERVER_OFFLINE_MESSAGE")
+                .unwrap_or_else(|| DEFAULT_SERVER_OFFLINE_MESSAGE.to_string()),
+            offline_alerts: env.flag("OFFLINE_ALERTS"),
+            idle_unload_after: match env.parse("IDLE_UNLOAD_SECS", 0) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            unload_command: env.non_empty("UNLOAD_COMMAND"),
+            wake_command: env.non_empty("WAKE_COMMAND"),
+            proxy: env.non_empty("PROXY"),
+            server_retries: env.parse("SERVER_RETRIES", 2),
+            show_retries: env.flag("SHOW_RETRIES"),
+            keep_alive_interval: match env.parse("KEEP_ALIVE_SECS", 0) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            load_max_tokens: match env.parse("LOAD_MAX_TOKENS", 0) {
+                0 => None,
+                max_tokens => Some(max_tokens),
+            },
+            load_idle_slots: env.parse("LOAD_IDLE_SLOTS", 0),
+            load_poll_interval: Duration::from_secs(env.parse("LOAD_POLL_SECS", 15)),
+            repetition_limit: env.parse("REPETITION_LIMIT", 3),
+            // llama.cpp only checks the key when it was started with --api-key
+            api_keys: std::env::var("API_KEY")
+                .unwrap_or_else(|_| "amogus".to_string())
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(String::from)
+                .collect(),
+            api_key_cooldown: Duration::from_secs(env.parse("API_KEY_COOLDOWN_SECS", 60)),
+            // A single llama.cpp server ignores the model, it only matters with a router in front
+            model: std::env::var("MODEL").unwrap_or_else(|_| "amogus".to_string()),
+            chat_path: env
+                .non_empty("CHAT_PATH")
+                .unwrap_or_else(|| "/v1/chat/completions".to_string()),
+            health_path: env
+                .non_empty("HEALTH_PATH")
+                .unwrap_or_else(|| "/health".to_string()),
+            model_aliases: env.aliases("MODEL_ALIASES"),
+            model_profiles: env.json_object("MODEL_PROFILES"),
+            command_aliases: env
+                .aliases("COMMAND_ALIASES")
+                .into_iter()
+                .map(|(alias, command)| {
+                    let strip = |name: &str| name.trim_start_matches('/').to_lowercase();
+                    (strip(&alias), strip(&command))
+                })
+                .collect(),
+            daily_request_limit: match env.parse("DAILY_REQUEST_LIMIT", 0) {
+                0 => None,
+                limit => Some(limit),
+            },
+            daily_limit_offset: env
+                .parse("DAILY_LIMIT_UTC_OFFSET", FixedOffset::east_opt(0).unwrap()),
+            daily_limit_file: std::env::var("DAILY_LIMIT_FILE")
+                .unwrap_or_else(|_| "daily_requests.txt".to_string()),
+            welcome_notice: match std::env::var("WELCOME_NOTICE") {
+                Ok(notice) if notice.trim().is_empty() => None,
+                Ok(notice) => Some(notice),
+                Err(_) => Some(DEFAULT_WELCOME_NOTICE.to_string()),
+            },
+            welcomed_file: std::env::var("WELCOMED_FILE")
+                .unwrap_or_else(|_| "welcomed_users.txt".to_string()),
+            maintenance_windows: env.time_windows("MAINTENANCE_WINDOWS"),
+            maintenance_offset: env
+                .parse("MAINTENANCE_UTC_OFFSET", FixedOffset::east_opt(0).unwrap()),
+            welcome_sticker: env.non_empty("WELCOME_STICKER"),
+            welcome_photo: env.non_empty("WELCOME_PHOTO"),
+            tts_url: env.non_empty("TTS_URL"),
+            tts_model: env
+                .non_empty("TTS_MODEL")
+                .unwrap_or_else(|| "tts-1".to_string()),
+            tts_voice: env.non_empty("TTS_VOICE"),
+            personas: env.personas("PERSONAS"),
+            quote_prompts: env.flag("QUOTE_PROMPTS"),
+            link_previews: env.flag("LINK_PREVIEWS"),
+            health_reactions: env.flag("HEALTH_REACTIONS"),
+            injection_guard: env.parse("INJECTION_GUARD", InjectionGuard::Off),
+            time_budget_secs: env.parse("RESPONSE_TIME_BUDGET_SECS", 0),
+        };
+
+        let mut problems = env.problems;
+        problems.extend(config.validate());
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Reads `.env` again, overriding the variables it sets, and builds the config from them.
+    /// Variables that were removed from the file keep their old value until a restart.
+    pub fn reload() -> Result<Self, Vec<String>> {
+        dotenvy::dotenv_override().map_err(|e| vec![format!("Couldn't read .env: {}", e)])?;
+        Self::from_env()
+    }
+
+    /// The names of the settings that differ between `self` and `other`.
+    pub fn changed_fields(&self, other: &Config) -> Vec<String> {
+        debug_fields(self)
+            .into_iter()
+            .zip(debug_fields(other))
+            .filter(|(old, new)| old != new)
+            .map(|((name, _), _)| name)
+            .collect()
+    }
+
+    /// The `Authorization` header for requests to the server with `key`, `None` if it's invalid.
+    pub fn authorization(key: &str) -> Option<HeaderValue> {
+        let mut header = HeaderValue::from_str(&format!("Bearer {}", key)).ok()?;
+        header.set_sensitive(true);
+        Some(header)
+    }
+
+    /// The current day for the daily limit.
+    pub fn today(&self) -> NaiveDate {
+        Utc::now()
+            .with_timezone(&self.daily_limit_offset)
+            .date_naive()
+    }
+
+    /// The end of the maintenance window the bot is in right now, `None` outside of them.
+    pub fn maintenance_until(&self) -> Option<NaiveTime> {
+        let now = Utc::now().with_timezone(&self.maintenance_offset).time();
+        self.maintenance_windows
+            .iter()
+            .find(|&&(start, end)| {
+                if start <= end {
+                    (start..end).contains(&now)
+                } else {
+                    now >= start || now < end
+                }
+            })
+            .map(|&(_, end)| end)
+    }
+
+    /// Whether the bot has to restart before a change to `field` takes effect.
+    pub fn needs_restart(field: &str) -> bool {
+        RESTART_ONLY.contains(&field)
+    }
+
+    /// Checks the values that parsed fine but still make no sense together.
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(proxy) = &self.proxy {
+            if let Err(e) = reqwest::Proxy::all(proxy) {
+                problems.push(format!("The proxy URL is invalid: {}", e));
+            }
+        }
+        for (name, path) in [
+            ("CHAT_PATH", &self.chat_path),
+            ("HEALTH_PATH", &self.health_path),
+        ] {
+            if !path.starts_with('/') {
+                problems.push(format!("{} must start with a /, got {:?}", name, path));
+            }
+        }
+        for (model, profile) in &self.model_profiles {
+            if !profile.is_object() {
+                problems.push(format!(
+                    "MODEL_PROFILES: the profile of {:?} must be a JSON object",
+                    model
+                ));
+            }
+        }
+        for (i, key) in self.api_keys.iter().enumerate() {
+            if Self::authorization(key).is_none() {
+                problems.push(format!(
+                    "API_KEY: key {} can only contain visible ASCII characters",
+                    i + 1
+                ));
+            }
+        }
+        if let Some(tts_url) = &self.tts_url {
+            if let Err(e) = reqwest::Url::parse(tts_url) {
+                problems.push(format!("TTS_URL {:?} is invalid: {}", tts_url, e));
+            }
+        }
+        if self.max_concurrent_requests == 0 {
+            problems.push("MAX_CONCURRENT_REQUESTS must be at least 1".to_string());
+        }
+        if self.max_tokens == 0 {
+            problems.push("MAX_TOKENS must be at least 1".to_string());
+        }
+        if self.max_tokens > self.max_tokens_limit {
+            problems.push(format!(
+                "MAX_TOKENS ({}) can't be higher than MAX_TOKENS_LIMIT ({})",
+                self.max_tokens, self.max_tokens_limit
+            ));
+        }
+        if self.history_turns > self.max_history_turns {
+            problems.push(format!(
+                "HISTORY_TURNS ({}) can't be higher than MAX_HISTORY_TURNS ({})",
+                self.history_turns, self.max_history_turns
+            ));
+        }
+        // Telegram rate limits edits, anything faster just gets the bot throttled
+        if self.placeholder_frames.len() > 1
+            && self.placeholder_interval < Duration::from_millis(500)
+        {
+            problems.push("PLACEHOLDER_INTERVAL_MS must be at least 500".to_string());
+        }
+        if self.startup_timeout.is_zero() {
+            problems.push("STARTUP_TIMEOUT_SECS must be positive".to_string());
+        }
+        // Polling without a pause would keep the server busy by itself
+        if self.load_max_tokens.is_some() && self.load_poll_interval.is_zero() {
+            problems.push("LOAD_POLL_SECS must be positive".to_string());
+        }
+        if self.idle_unload_after.is_some()
+            && (self.unload_command.is_none() || self.wake_command.is_none())
+        {
+            problems.push(
+                "IDLE_UNLOAD_SECS needs both UNLOAD_COMMAND and WAKE_COMMAND to be set".to_string(),
+            );
+        }
+        if self.model.trim().is_empty() {
+            problems.push("MODEL can't be empty".to_string());
+        }
+        // Every request already has these, and EXTRA_PARAMS never overrides what's there
+        for (param, instead) in [
+            ("temperature", "MODEL_PROFILES"),
+            ("frequency_penalty", "MODEL_PROFILES"),
+            ("max_tokens", "MAX_TOKENS"),
+        ] {
+            if self.extra_params.contains_key(param) {
+                problems.push(format!(
+                    "EXTRA_PARAMS: {} is always sent with its default, set it in {} instead",
+                    param, instead
+                ));
+            }
+        }
+        let param_sets = std::iter::once(("EXTRA_PARAMS".to_string(), &self.extra_params)).chain(
+            self.model_profiles.iter().filter_map(|(model, profile)| {
+                Some((format!("MODEL_PROFILES {:?}", model), profile.as_object()?))
+            }),
+        );
+        for (source, params) in param_sets {
+            for (param, range) in [
+                ("temperature", 0.0..=2.0),
+                ("top_p", 0.0..=1.0),
+                ("min_p", 0.0..=1.0),
+            ] {
+                let Some(value) = params.get(param) else {
+                    continue;
+                };
+                if !value.as_f64().is_some_and(|value| range.contains(&value)) {
+                    problems.push(format!(
+                        "{}: {} must be a number between {} and {}, got {}",
+                        source,
+                        param,
+                        range.start(),
+                        range.end(),
+                        value
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Whether a chat with these settings remembers previous messages, see `/memory off`.
+    pub fn history_enabled(&self, settings: &ChatSettings) -> bool {
+        settings.history_enabled.unwrap_or(self.history_enabled)
+    }
+
+    /// How many history turns are remembered in a chat with these settings, 0 with memory off.
+    pub fn history_turns(&self, settings: &ChatSettings) -> usize {
+        if !self.history_enabled(settings) {
+            return 0;
+        }
+        settings.history_turns.unwrap_or(self.history_turns)
+    }
+
+    /// The `max_tokens` cap for a chat with these settings.
+    pub fn max_tokens(&self, settings: &ChatSettings) -> u32 {
+        settings.max_tokens.unwrap_or(self.max_tokens)
+    }
+
+    /// How long an answer may take in a chat with these settings, `None` if there's no limit.
+    pub fn time_budget(&self, settings: &ChatSettings) -> Option<Duration> {
+        let secs = settings.time_budget_secs.unwrap_or(self.time_budget_secs);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// The model id sent in requests from a chat with these settings.
+    pub fn model_id<'a>(&'a self, settings: &'a ChatSettings) -> &'a str {
+        self.resolve_model(settings.model.as_deref().unwrap_or(&self.model))
+    }
+
+    /// The sampling parameters configured for the chat's model, if there are any.
+    pub fn model_profile(&self, settings: &ChatSettings) -> Option<&Map<String, Value>> {
+        self.model_profiles
+            .get(self.model_id(settings))
+            .and_then(Value::as_object)
+    }
+
+    /// Turns an alias into the model id it stands for, anything else is taken as an id already.
+    pub fn resolve_model<'a>(&'a self, name: &'a str) -> &'a str {
+        self.model_aliases
+            .iter()
+            .find(|(alias, _)| alias == name)
+            .map_or(name, |(_, id)| id)
+    }
+
+    /// The system prompt of the chat's persona, `None` without one.
+    pub fn system_prompt(&self, settings: &ChatSettings) -> Option<&str> {
+        let persona = settings.persona.as_deref()?;
+        self.personas
+            .iter()
+            .find(|(name, _)| name == persona)
+            .map(|(_, prompt)| prompt.as_str())
+    }
+
+    /// Prompt (or answer) text as it should appear in the logs. With `LOG_PROMPTS` off
+    /// the hash still shows when the same prompt is sent again, without revealing it.
+    pub fn loggable(&self, text: &str) -> String {
+        if self.log_prompts {
+            return text.to_string();
+        }
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        format!(
+            "<{} chars, hash {:016x}>",
+            text.chars().count(),
+            hasher.finish()
+        )
+    }
+
+    /// Whether the sender of `msg` is one of the configured admins.
+    /// Channel posts don't have a sender, so they never count as admin.
+    pub fn is_admin(&self, msg: &Message) -> bool {
+        msg.from()
+            .is_some_and(|user| self.admin_ids.contains(&user.id))
+    }
+}
+
+/// Splits the Debug output of `config` into its fields, so configs can be compared field by field
+/// without listing every field here.
+fn debug_fields(config: &Config) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in format!("{:#?}", config).lines() {
+        // Top level fields are indented once, anything deeper belongs to the field above
+        if let Some(field) = line
+            .strip_prefix("    ")
+            .filter(|field| !field.starts_with(' '))
+        {
+            let name = field.split(':').next().unwrap_or_default().to_string();
+            fields.push((name, field.to_string()));
+        } else if let Some((_, value)) = fields.last_mut() {
+            value.push_str(line);
+        }
+    }
+    fields
+}
+
+/// Reads values from the environment, remembering the invalid ones instead of failing right away.
+#[derive(Default)]
+struct Env {
+    problems: Vec<String>,
+}
+
+impl Env {
+    /// Reads a boolean, `true`/`1` and `false`/`0`.
+    fn flag(&mut self, name: &str) -> bool {
+        self.flag_or(name, false)
+    }
+
+    /// Like `flag`, but `default` when it's missing or empty.
+    fn flag_or(&mut self, name: &str, default: bool) -> bool {
+        match std::env::var(name) {
+            Ok(value) => match value.trim() {
+                "true" | "1" => true,
+                "false" | "0" => false,
+                "" => default,
+                _ => {
+                    self.problems
+                        .push(format!("{}: expected true or false, got {:?}", name, value));
+                    default
+                }
+            },
+            Err(_) => default,
+        }
+    }
+
+    /// Parses a value, `default` when it's missing.
+    fn parse<T: std::str::FromStr>(&mut self, name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                self.problems
+                    .push(format!("{}: invalid value {:?}", name, value));
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Reads a string that's only set when it isn't empty, like a shell command or a URL.
+    fn non_empty(&self, name: &str) -> Option<String> {
+        std::env::var(name)
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+    }
+
+    /// Reads comma separated `alias=value` pairs.
+    fn aliases(&mut self, name: &str) -> Vec<(String, String)> {
+        std::env::var(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| match pair.split_once('=') {
+                Some((alias, value)) if !alias.trim().is_empty() && !value.trim().is_empty() => {
+                    Some((alias.trim().to_string(), value.trim().to_string()))
+                }
+                _ => {
+                    self.problems
+                        .push(format!("{}: expected alias=value, got {:?}", name, pair));
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Reads comma separated `HH:MM-HH:MM` time windows.
+    fn time_windows(&mut self, name: &str) -> Vec<(NaiveTime, NaiveTime)> {
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+        std::env::var(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .filter_map(|window| {
+                let parsed = window
+                    .split_once('-')
+                    .and_then(|(start, end)| Some((parse(start)?, parse(end)?)));
+                if parsed.is_none() {
+                    self.problems
+                        .push(format!("{}: expected HH:MM-HH:MM, got {:?}", name, window));
+                }
+                parsed
+            })
+            .collect()
+    }
+
+    /// Reads a JSON object of persona names and their system prompts.
+    fn personas(&mut self, name: &str) -> Vec<(String, String)> {
+        self.json_object(name)
+            .into_iter()
+            .filter_map(|(persona, prompt)| match prompt {
+                Value::String(prompt) if !prompt.trim().is_empty() => Some((persona, prompt)),
+                _ => {
+                    self.problems.push(format!(
+                        "{}: the system prompt of {:?} must be a non-empty string",
+                        name, persona
+                    ));
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Reads a JSON object, an empty one if it's missing.
+    fn json_object(&mut self, name: &str) -> Map<String, Value> {
+        let Ok(value) = std::env::var(name) else {
+            return Map::new();
+        };
+        if value.trim().is_empty() {
+            return Map::new();
+        }
+        match serde_json::from_str(&value) {
+            Ok(Value::Object(object)) => object,
+            _ => {
+                self.problems
+                    .push(format!("{}: expected a JSON object, got {}", name, value));
+                Map::new()
+            }
+        }
+    }
+
+    /// Reads a JSON array of strings, `None` if it's missing so a default can be used.
+    fn json_strings(&mut self, name: &str) -> Option<Vec<String>> {
+        let value = self.non_empty(name)?;
+        match serde_json::from_str(&value) {
+            Ok(strings) => Some(strings),
+            Err(_) => {
+                self.problems.push(format!(
+                    "{}: expected a JSON array of strings, got {}",
+                    name, value
+                ));
+                None
+            }
+        }
+    }
+}
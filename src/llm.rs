@@ -0,0 +1,919 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, AUTHORIZATION, RETRY_AFTER};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use teloxide::prelude::*;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    config::Config,
+    placeholder::RetryNotice,
+    state::{ChatSettings, PromptFormat, Turn},
+    typing::TypingIndicator,
+};
+
+pub const URL: &str = "http://192.168.2.56:8080";
+
+// Delay before the first retry of an overloaded server, doubled after every failed one
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(8);
+
+// The diagnostics are only a side note to an error, they shouldn't hang around for long
+const DIAGNOSTICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Every server the bot knows about, checked by `/diag`. There's only the one for now.
+pub const BACKENDS: &[(&str, &str)] = &[("primary", URL)];
+
+pub enum CompletionError {
+    Send(reqwest::Error),
+    Read(reqwest::Error),
+    InvalidChunk(serde_json::Error),
+    Cancelled,
+}
+
+impl CompletionError {
+    /// Whether the server couldn't be connected to at all, e.g. because it isn't running.
+    pub fn is_server_offline(&self) -> bool {
+        matches!(self, CompletionError::Send(e) if e.is_connect())
+    }
+
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            CompletionError::Send(_) => "An error occurred while sending the request.",
+            CompletionError::Read(_) => "An error occurred while reading the response.",
+            CompletionError::InvalidChunk(_) => "An error occurred while parsing the response.",
+            CompletionError::Cancelled => "Stopped.",
+        }
+    }
+}
+
+impl std::fmt::Display for CompletionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompletionError::Send(e) => write!(f, "Error sending request: {}", e),
+            CompletionError::Read(e) => write!(f, "Error reading response: {}", e),
+            CompletionError::InvalidChunk(e) => write!(f, "Error parsing stream chunk: {}", e),
+            CompletionError::Cancelled => write!(f, "Request was cancelled"),
+        }
+    }
+}
+
+/// How a streamed completion ended.
+pub enum StreamedCompletion {
+    /// The model finished on its own.
+    Finished(String),
+    /// The request was cancelled, this is everything generated until then.
+    Stopped(String),
+    /// The server refused the request, this is the error body it sent instead of a stream.
+    Rejected(String),
+}
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+// API keys the server rate limited and when they can be used again. Keyed by the key itself
+// instead of its position, so reordering `API_KEY` with /reload doesn't mix them up.
+static KEY_COOLDOWNS: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+// Whether the last health check found the server busy, see `spawn_load_monitor`
+static UNDER_LOAD: AtomicBool = AtomicBool::new(false);
+
+/// Builds the shared client, going through `proxy` if one is configured. `NO_PROXY` still applies,
+/// so a server on the local network can be reached directly.
+pub fn init_client(proxy: Option<&str>) -> reqwest::Result<()> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(reqwest::NoProxy::from_env()));
+    }
+    let _ = CLIENT.set(builder.build()?);
+    Ok(())
+}
+
+/// The HTTP client used for every request to the server. Sharing it keeps connections pooled,
+/// so requests don't have to wait for a new connection first.
+pub fn client() -> &'static reqwest::Client {
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// Pings the health check at `health_path` every `interval` in the background, so the pooled
+/// connection doesn't time out between requests.
+pub fn spawn_keep_alive(interval: Duration, health_path: String) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match client().get(format!("{}{}", URL, health_path)).send().await {
+                Ok(response) => debug!("Keep-alive ping: {}", response.status()),
+                Err(e) => debug!("Keep-alive ping failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Polls the health check at `health_path` every `interval` in the background and remembers
+/// whether the server has `busy_idle_slots` idle slots or fewer. While it does, new requests
+/// get `LOAD_MAX_TOKENS` at most, so the queue moves faster.
+pub fn spawn_load_monitor(interval: Duration, health_path: String, busy_idle_slots: u32) {
+    tokio::spawn(async move {
+        loop {
+            let health = match client().get(format!("{}{}", URL, health_path)).send().await {
+                Ok(response) => response.json::<Value>().await.ok(),
+                Err(e) => {
+                    debug!("Load check failed: {}", e);
+                    None
+                }
+            };
+            // An unreachable server or one that doesn't report its slots doesn't count as busy
+            let busy = health.is_some_and(|health| is_busy(&health, busy_idle_slots));
+            if UNDER_LOAD.swap(busy, Ordering::Relaxed) != busy {
+                if busy {
+                    info!("The server is busy, lowering max_tokens of new requests");
+                } else {
+                    info!("The server isn't busy anymore, restoring max_tokens");
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+fn is_busy(health: &Value, busy_idle_slots: u32) -> bool {
+    health["slots_idle"]
+        .as_u64()
+        .is_some_and(|idle| idle <= u64::from(busy_idle_slots))
+}
+
+/// Sampling parameters shared by the chat and text completion endpoints.
+fn base_request_body(config: &Config, settings: &ChatSettings) -> Value {
+    let mut body = json!({
+        "model": config.model_id(settings),
+        "temperature": 0.4, // low temperature because this model is so small any variation will probably be bad
+        "max_tokens": config.max_tokens(settings), // sometimes the model generates infinite tokens, always send a cap
+        "frequency_penalty": 1.1, // sometimes the model repeats itself
+    });
+    // The model's profile replaces the defaults above, but not the chat's own /maxtokens
+    if let Some(profile) = config.model_profile(settings) {
+        for (name, value) in profile {
+            if name == "max_tokens" && settings.max_tokens.is_some() {
+                continue;
+            }
+            body[name] = value.clone();
+        }
+    }
+    if let Some(load_max_tokens) = config.load_max_tokens {
+        let max_tokens = body["max_tokens"].as_u64().unwrap_or(u64::MAX);
+        if UNDER_LOAD.load(Ordering::Relaxed) && max_tokens > u64::from(load_max_tokens) {
+            body["max_tokens"] = json!(load_max_tokens);
+        }
+    }
+    if !settings.logit_bias.is_empty() {
+        let logit_bias: serde_json::Map<String, Value> = settings
+            .logit_bias
+            .iter()
+            .map(|(token, bias)| (token.to_string(), json!(bias)))
+            .collect();
+        body["logit_bias"] = Value::Object(logit_bias);
+    }
+    body
+}
+
+/// Adds the configured extra sampler params to `body`, without overwriting anything already in it.
+fn merge_extra_params(body: &mut Value, extra_params: &serde_json::Map<String, Value>) {
+    let Some(body) = body.as_object_mut() else {
+        return;
+    };
+    for (key, value) in extra_params {
+        if body.contains_key(key) {
+            debug!("Not overriding {} with the extra param", key);
+        } else {
+            body.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// The messages sent for `prompt`, with the history turns before it and the summary of the
+/// turns that were dropped from it in the system prompt.
+fn chat_messages(
+    config: &Config,
+    prompt: &str,
+    summary: Option<&str>,
+    history: &[Turn],
+    settings: &ChatSettings,
+) -> Vec<Value> {
+    let mut system = Vec::new();
+    if let Some(persona) = config.system_prompt(settings) {
+        system.push(persona.to_string());
+    }
+    if let Some(answer_format) = settings.answer_format {
+        system.push(answer_format.instruction().to_string());
+    }
+    // The model sometimes answers in English no matter what, so tell it which language to use
+    if config.detect_language {
+        if let Some(language) = detect_language(prompt) {
+            system.push(format!("Always respond in {}.", language));
+        }
+    }
+
+    if let Some(summary) = summary {
+        system.push(format!("Summary of the earlier conversation: {}", summary));
+    }
+
+    let mut messages = Vec::new();
+    if !system.is_empty() {
+        messages.push(json!({
+            "role": "system",
+            "content": system.join("\n\n")
+        }));
+    }
+    for turn in history {
+        messages.push(json!({
+            "role": "user",
+            "content": turn.prompt
+        }));
+        messages.push(json!({
+            "role": "assistant",
+            "content": turn.response
+        }));
+    }
+    messages.push(json!({
+        "role": "user",
+        "content": prompt
+    }));
+    messages
+}
+
+/// Builds the chat completions request for `prompt`, with the history turns before it.
+pub fn chat_request_body(
+    config: &Config,
+    prompt: &str,
+    summary: Option<&str>,
+    history: &[Turn],
+    settings: &ChatSettings,
+) -> Value {
+    let mut body = base_request_body(config, settings);
+    body["messages"] = json!(chat_messages(config, prompt, summary, history, settings));
+    // Keeps the `<think>` block of reasoning models in the content instead of a separate field,
+    // `/qwen` strips it and `/think` shows it
+    body["reasoning_format"] = json!("none");
+    merge_extra_params(&mut body, &config.extra_params);
+    if config.request_template.is_empty() {
+        return body;
+    }
+
+    let values = [
+        ("prompt", json!(prompt)),
+        ("model", body["model"].take()),
+        ("messages", body["messages"].take()),
+        ("max_tokens", body["max_tokens"].take()),
+    ];
+    fill_template(&Value::Object(config.request_template.clone()), &values)
+}
+
+/// Replaces the `{{name}}` placeholders in the strings of `template`. A string that's nothing but
+/// a placeholder becomes the value itself, so `"{{messages}}"` turns into the array.
+fn fill_template(template: &Value, values: &[(&str, Value)]) -> Value {
+    match template {
+        Value::String(text) => {
+            let mut filled = text.clone();
+            for (name, value) in values {
+                let placeholder = format!("{{{{{}}}}}", name);
+                if *text == placeholder {
+                    return value.clone();
+                }
+                let replacement = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                filled = filled.replace(&placeholder, &replacement);
+            }
+            Value::String(filled)
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| fill_template(item, values))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), fill_template(value, values)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// Adds `stop` to the stop sequences of `body`, keeping the ones it already has.
+pub fn add_stop_sequences(body: &mut Value, stop: &[&str]) {
+    if stop.is_empty() {
+        return;
+    }
+    let mut sequences = match body["stop"].take() {
+        Value::Array(sequences) => sequences,
+        Value::String(sequence) => vec![json!(sequence)],
+        _ => Vec::new(),
+    };
+    sequences.extend(stop.iter().map(|sequence| json!(sequence)));
+    body["stop"] = json!(sequences);
+}
+
+/// Builds the request for `prompt` in the chat's prompt format.
+/// Returns the endpoint to send it to along with the body.
+pub fn conversation_request<'a>(
+    config: &'a Config,
+    prompt: &str,
+    summary: Option<&str>,
+    history: &[Turn],
+    settings: &ChatSettings,
+) -> (&'a str, Value) {
+    match settings.prompt_format {
+        PromptFormat::Chat => (
+            &config.chat_path,
+            chat_request_body(config, prompt, summary, history, settings),
+        ),
+        PromptFormat::Instruct => {
+            // Same conversation, but we apply Qwen's ChatML template ourselves instead of the server
+            let mut text = String::new();
+            for message in chat_messages(config, prompt, summary, history, settings) {
+                text.push_str(&format!(
+                    "<|im_start|>{}\n{}<|im_end|>\n",
+                    message["role"].as_str().unwrap_or_default(),
+                    message["content"].as_str().unwrap_or_default()
+                ));
+            }
+            text.push_str("<|im_start|>assistant\n");
+
+            let mut body = base_request_body(config, settings);
+            body["prompt"] = json!(text);
+            body["stop"] = json!(["<|im_end|>"]);
+            merge_extra_params(&mut body, &config.extra_params);
+            ("/v1/completions", body)
+        }
+    }
+}
+
+/// Sends `prompt` to the chat completions endpoint while showing the typing indicator
+/// and returns the unparsed response body.
+pub async fn request_completion(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    prompt: &str,
+    settings: &ChatSettings,
+    cancel: &CancellationToken,
+) -> Result<String, CompletionError> {
+    let body = chat_request_body(config, prompt, None, &[], settings);
+    let typing = typing_target(bot, msg, settings);
+    send_completion_request(typing, config, &config.chat_path, &body, cancel).await
+}
+
+/// Sends `prompt` as-is to the text completions endpoint, skipping the chat template.
+pub async fn request_text_completion(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    prompt: &str,
+    settings: &ChatSettings,
+    cancel: &CancellationToken,
+) -> Result<String, CompletionError> {
+    let mut body = base_request_body(config, settings);
+    body["prompt"] = json!(prompt);
+    merge_extra_params(&mut body, &config.extra_params);
+
+    let typing = typing_target(bot, msg, settings);
+    send_completion_request(typing, config, "/v1/completions", &body, cancel).await
+}
+
+/// Posts `body` to `path` on the server, giving up if `cancel` fires before the response arrives.
+/// `retry_notice` is told while a failed request is being retried.
+async fn post(
+    config: &Config,
+    path: &str,
+    body: &Value,
+    retry_notice: Option<&RetryNotice>,
+    cancel: &CancellationToken,
+) -> Result<reqwest::Response, CompletionError> {
+    let url = format!("{}{}", URL, path);
+
+    info!("Sending request to {}", url);
+    debug!("Request body: {}", config.loggable(&body.to_string()));
+    let mut attempt = 0;
+    loop {
+        // The JSON body sets the content type, the API keys were validated at startup.
+        // When every key is rate limited the first one is sent anyway, the server decides.
+        let key = api_key(&config.api_keys).or((!config.api_keys.is_empty()).then_some(0));
+        let mut headers = HeaderMap::new();
+        if let Some(i) = key {
+            if let Some(authorization) = Config::authorization(&config.api_keys[i]) {
+                headers.insert(AUTHORIZATION, authorization);
+            }
+            if config.api_keys.len() > 1 {
+                debug!("Using API key {}/{}", i + 1, config.api_keys.len());
+            }
+        }
+
+        // Dropping the request closes the connection, which makes llama.cpp stop generating
+        let res = tokio::select! {
+            res = client().post(&url).headers(headers).json(body).send() => res.map_err(CompletionError::Send)?,
+            _ = cancel.cancelled() => return Err(CompletionError::Cancelled),
+        };
+        if let (Some(i), reqwest::StatusCode::TOO_MANY_REQUESTS) = (key, res.status()) {
+            let cooldown = retry_after(&res).unwrap_or(config.api_key_cooldown);
+            cool_down_api_key(&config.api_keys[i], Instant::now() + cooldown);
+            // Every switch puts a key on cooldown, so this stops once all of them are
+            if let Some(next) = api_key(&config.api_keys) {
+                warn!(
+                    "API key {}/{} is rate limited for {}s, switching to key {}",
+                    i + 1,
+                    config.api_keys.len(),
+                    cooldown.as_secs(),
+                    next + 1
+                );
+                continue;
+            }
+            warn!("Every API key is rate limited");
+        }
+        // A 4xx won't go away by sending the same request again
+        if !is_retryable(res.status()) || attempt >= config.server_retries {
+            if let Some(retry_notice) = retry_notice {
+                retry_notice.set(false);
+            }
+            return Ok(res);
+        }
+
+        let delay = retry_delay(attempt, jitter());
+        warn!(
+            "Server returned {}, retrying in {}ms ({}/{})",
+            res.status(),
+            delay.as_millis(),
+            attempt + 1,
+            config.server_retries
+        );
+        if let Some(retry_notice) = retry_notice {
+            retry_notice.set(true);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => return Err(CompletionError::Cancelled),
+        }
+        attempt += 1;
+    }
+}
+
+/// A short summary of what the server's request slots are doing, to compare a failed request
+/// with. Needs llama.cpp's `/slots` endpoint, which it leaves out when started with `--no-slots`.
+pub async fn server_diagnostics(config: &Config) -> String {
+    let mut request = client()
+        .get(format!("{}/slots", URL))
+        .timeout(DIAGNOSTICS_TIMEOUT);
+    if let Some(authorization) =
+        api_key(&config.api_keys).and_then(|i| Config::authorization(&config.api_keys[i]))
+    {
+        request = request.header(AUTHORIZATION, authorization);
+    }
+    match request.send().await {
+        Ok(res) if res.status().is_success() => match res.json::<Value>().await {
+            Ok(slots) => summarize_slots(&slots),
+            Err(e) => format!("unreadable /slots answer: {}", e.without_url()),
+        },
+        Ok(res) => format!("/slots answered {}", res.status()),
+        Err(e) => format!("/slots unreachable: {}", e.without_url()),
+    }
+}
+
+fn summarize_slots(slots: &Value) -> String {
+    let Some(slots) = slots.as_array() else {
+        return "unexpected /slots answer".to_string();
+    };
+    if slots.is_empty() {
+        return "no slots".to_string();
+    }
+    slots
+        .iter()
+        .map(|slot| {
+            // Older llama.cpp versions have a state instead, 1 meaning busy
+            let busy = slot["is_processing"]
+                .as_bool()
+                .unwrap_or(slot["state"] == 1);
+            let mut summary = format!("slot {} {}", slot["id"], if busy { "busy" } else { "idle" });
+            if let Some(n_ctx) = slot["n_ctx"].as_u64() {
+                summary.push_str(&format!(", context {}", n_ctx));
+            }
+            // Newer versions put the next token in a list
+            let next_token = match &slot["next_token"] {
+                Value::Array(tokens) => tokens.first().unwrap_or(&Value::Null),
+                next_token => next_token,
+            };
+            if let Some(decoded) = next_token["n_decoded"].as_u64() {
+                summary.push_str(&format!(", {} tokens decoded", decoded));
+            }
+            summary
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// The first of `keys` that isn't rate limited, `None` if there are no keys or all of them are.
+pub fn api_key(keys: &[String]) -> Option<usize> {
+    let cooldowns = KEY_COOLDOWNS.lock().unwrap();
+    available_key(keys, &cooldowns, Instant::now())
+}
+
+fn available_key(keys: &[String], cooldowns: &[(String, Instant)], now: Instant) -> Option<usize> {
+    keys.iter().position(|key| {
+        !cooldowns
+            .iter()
+            .any(|(limited, until)| limited == key && *until > now)
+    })
+}
+
+/// Skips `key` until `until`, also forgetting the cooldowns that are over.
+fn cool_down_api_key(key: &str, until: Instant) {
+    let now = Instant::now();
+    let mut cooldowns = KEY_COOLDOWNS.lock().unwrap();
+    cooldowns.retain(|(limited, limited_until)| limited != key && *limited_until > now);
+    cooldowns.push((key.to_string(), until));
+}
+
+/// How long the server asked to wait with `Retry-After`, only the seconds form is understood.
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let secs = res
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+/// Whether the status means the server is only overloaded or restarting for a moment.
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 500 | 502 | 503 | 504)
+}
+
+/// How long to wait before retry number `attempt` (starting at 0). `jitter` between 0 and 1
+/// adds up to half the delay again, so requests that failed together don't all retry together.
+pub fn retry_delay(attempt: u32, jitter: f64) -> Duration {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RETRY_MAX_DELAY);
+    delay.mul_f64(1.0 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// A random number between 0 and 1, good enough for spreading out retries without a rand dependency.
+pub fn jitter() -> f64 {
+    (RandomState::new().hash_one(Instant::now()) % 1000) as f64 / 1000.0
+}
+
+/// Where the typing indicator is shown for a request answering `msg`, `None` if the chat turned it off.
+pub fn typing_target<'a>(
+    bot: &'a Bot,
+    msg: &Message,
+    settings: &ChatSettings,
+) -> Option<(&'a Bot, ChatId)> {
+    (!settings.hide_typing).then_some((bot, msg.chat.id))
+}
+
+#[instrument(skip_all, fields(path = %path))]
+async fn send_completion_request(
+    typing: Option<(&Bot, ChatId)>,
+    config: &Config,
+    path: &str,
+    body: &Value,
+    cancel: &CancellationToken,
+) -> Result<String, CompletionError> {
+    // Before we send the request, send the typing indicator
+    let typing = typing.map(|(bot, chat_id)| TypingIndicator::start(bot, chat_id));
+
+    let now = Instant::now();
+    let res = post(config, path, body, None, cancel).await;
+    info!("Request took {}ms", now.elapsed().as_millis());
+    drop(typing);
+
+    res?.text().await.map_err(CompletionError::Read)
+}
+
+/// Returns a child of `cancel` that's also cancelled once `budget` runs out, so `/stop`,
+/// a deleted placeholder and the time budget all end a request the same way.
+pub fn with_time_budget(cancel: &CancellationToken, budget: Option<Duration>) -> CancellationToken {
+    let token = cancel.child_token();
+    if let Some(budget) = budget {
+        let timer = token.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(budget) => timer.cancel(),
+                _ = timer.cancelled() => {}
+            }
+        });
+    }
+    token
+}
+
+/// Streams a chat or text completion from `path`, so that if `cancel` fires midway we can stop early
+/// and still return what the model came up with until then.
+#[instrument(skip_all, fields(path = %path))]
+pub async fn stream_completion(
+    typing: Option<(&Bot, ChatId)>,
+    config: &Config,
+    path: &str,
+    mut body: Value,
+    retry_notice: Option<&RetryNotice>,
+    cancel: &CancellationToken,
+) -> Result<StreamedCompletion, CompletionError> {
+    body["stream"] = json!(true);
+
+    let _typing = typing.map(|(bot, chat_id)| TypingIndicator::start(bot, chat_id));
+    let now = Instant::now();
+    let completion = read_stream(config, path, &body, retry_notice, cancel).await;
+    info!("Request took {}ms", now.elapsed().as_millis());
+
+    completion
+}
+
+#[instrument(skip_all)]
+async fn read_stream(
+    config: &Config,
+    path: &str,
+    body: &Value,
+    retry_notice: Option<&RetryNotice>,
+    cancel: &CancellationToken,
+) -> Result<StreamedCompletion, CompletionError> {
+    let res = post(config, path, body, retry_notice, cancel).await?;
+
+    // Errors aren't streamed, llama.cpp answers with a regular JSON body instead
+    if !res.status().is_success() {
+        let body = res.text().await.map_err(CompletionError::Read)?;
+        return Ok(StreamedCompletion::Rejected(body));
+    }
+
+    let mut stream = res.bytes_stream();
+    let mut buffer = Vec::new();
+    let mut content = String::new();
+    loop {
+        // Dropping the stream closes the connection, so the server stops generating
+        let chunk = tokio::select! {
+            chunk = stream.next() => chunk,
+            _ = cancel.cancelled() => return Ok(StreamedCompletion::Stopped(content)),
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        buffer.extend_from_slice(&chunk.map_err(CompletionError::Read)?);
+
+        for line in take_complete_lines(&mut buffer) {
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok(StreamedCompletion::Finished(content));
+            }
+
+            let event: Value = serde_json::from_str(data).map_err(|e| {
+                warn!("Couldn't parse stream event: {}", config.loggable(data));
+                CompletionError::InvalidChunk(e)
+            })?;
+            // Chat completions stream deltas, text completions stream plain text
+            let choice = &event["choices"][0];
+            if let Some(delta) = choice["delta"]["content"]
+                .as_str()
+                .or(choice["text"].as_str())
+            {
+                content.push_str(delta);
+            }
+            if choice["finish_reason"] == "length" {
+                warn!("Generation stopped at the max_tokens cap");
+            }
+        }
+    }
+
+    Ok(StreamedCompletion::Finished(content))
+}
+
+/// Takes the complete lines out of the bytes received so far, leaving the incomplete rest in
+/// `buffer`. Server-sent events are separated by newlines, and a newline byte is never part of a
+/// multi-byte UTF-8 character, so a character split across two chunks is only decoded once both
+/// halves are in. Invalid UTF-8 from the server becomes replacement characters instead of an error.
+fn take_complete_lines(buffer: &mut Vec<u8>) -> Vec<String> {
+    let Some(last_newline) = buffer.iter().rposition(|&byte| byte == b'\n') else {
+        return Vec::new();
+    };
+    let complete: Vec<u8> = buffer.drain(..=last_newline).collect();
+    complete
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect()
+}
+
+/// The body llama.cpp sends back with a non-2xx status.
+#[derive(Debug, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetails,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorDetails {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+}
+
+impl ErrorResponse {
+    /// Parses the body of a rejected request, `None` if it's not in llama.cpp's error format.
+    pub fn parse(body: &str) -> Option<Self> {
+        serde_json::from_str(body).ok()
+    }
+
+    /// Whether the server rejected the request because it doesn't fit in the model's context.
+    pub fn is_context_overflow(&self) -> bool {
+        self.error.kind.as_deref() == Some("exceed_context_size_error")
+            || self.error.message.to_lowercase().contains("context")
+    }
+
+    /// What to tell the user, the server's own message is usually clear enough to act on.
+    pub fn user_message(&self) -> String {
+        format!("The server rejected the request: {}", self.error.message)
+    }
+}
+
+/// Whether the server rejected the request because it doesn't fit in the model's context.
+pub fn is_context_overflow(body: &str) -> bool {
+    ErrorResponse::parse(body).is_some_and(|e| e.is_context_overflow())
+}
+
+/// Detects the language of `prompt`, `None` if whatlang isn't confident about it.
+fn detect_language(prompt: &str) -> Option<&'static str> {
+    let detected = whatlang::detect(prompt)?;
+    info!(
+        "Detected prompt language: {} (confidence {:.2})",
+        detected.lang().eng_name(),
+        detected.confidence()
+    );
+    detected.is_reliable().then(|| detected.lang().eng_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_placeholders_are_filled() {
+        let template = json!({
+            "model": "{{model}}",
+            "input": {"messages": "{{messages}}", "note": "asked: {{prompt}}"},
+            "options": [{"n_predict": "{{max_tokens}}"}],
+            "keep": true
+        });
+        let values = [
+            ("prompt", json!("hi")),
+            ("model", json!("qwen")),
+            ("messages", json!([{"role": "user", "content": "hi"}])),
+            ("max_tokens", json!(256)),
+        ];
+        assert_eq!(
+            fill_template(&template, &values),
+            json!({
+                "model": "qwen",
+                "input": {"messages": [{"role": "user", "content": "hi"}], "note": "asked: hi"},
+                "options": [{"n_predict": 256}],
+                "keep": true
+            })
+        );
+    }
+
+    #[test]
+    fn busy_with_few_idle_slots() {
+        assert!(is_busy(&json!({"status": "ok", "slots_idle": 0}), 0));
+        assert!(is_busy(&json!({"status": "ok", "slots_idle": 1}), 1));
+        assert!(!is_busy(&json!({"status": "ok", "slots_idle": 2}), 1));
+        // Newer llama.cpp versions leave the slots out of the health check
+        assert!(!is_busy(&json!({"status": "ok"}), 0));
+    }
+
+    #[test]
+    fn slots_are_summarized() {
+        let slots = json!([
+            {"id": 0, "is_processing": true, "n_ctx": 2048, "next_token": [{"n_decoded": 17}]},
+            {"id": 1, "state": 0, "n_ctx": 2048, "next_token": {"n_decoded": 0}}
+        ]);
+        assert_eq!(
+            summarize_slots(&slots),
+            "slot 0 busy, context 2048, 17 tokens decoded; slot 1 idle, context 2048, 0 tokens decoded"
+        );
+        assert_eq!(summarize_slots(&json!([])), "no slots");
+        assert_eq!(
+            summarize_slots(&json!({"error": "nope"})),
+            "unexpected /slots answer"
+        );
+    }
+
+    #[test]
+    fn rate_limited_keys_are_skipped() {
+        let keys = ["a".to_string(), "b".to_string()];
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        assert_eq!(available_key(&keys, &[], now), Some(0));
+        assert_eq!(
+            available_key(&keys, &[("a".to_string(), later)], now),
+            Some(1)
+        );
+        // An expired cooldown doesn't count anymore
+        assert_eq!(
+            available_key(&keys, &[("a".to_string(), now)], now),
+            Some(0)
+        );
+        let both = [("a".to_string(), later), ("b".to_string(), later)];
+        assert_eq!(available_key(&keys, &both, now), None);
+        assert_eq!(available_key(&[], &[], now), None);
+    }
+
+    #[test]
+    fn retry_delay_doubles_until_the_cap() {
+        let delays: Vec<_> = (0..6).map(|attempt| retry_delay(attempt, 0.0)).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 8000].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn retry_delay_adds_at_most_half_as_jitter() {
+        assert_eq!(retry_delay(0, 1.0), Duration::from_millis(750));
+        assert_eq!(retry_delay(2, 0.5), Duration::from_millis(2500));
+        assert_eq!(retry_delay(10, 1.0), Duration::from_secs(12));
+        // Out of range jitter is clamped instead of shrinking or blowing up the delay
+        assert_eq!(retry_delay(0, -1.0), Duration::from_millis(500));
+        assert_eq!(retry_delay(0, 5.0), Duration::from_millis(750));
+    }
+
+    #[test]
+    fn retry_delay_survives_huge_attempts() {
+        assert_eq!(retry_delay(u32::MAX, 0.0), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn jitter_stays_in_range() {
+        for _ in 0..100 {
+            assert!((0.0..1.0).contains(&jitter()));
+        }
+    }
+
+    #[test]
+    fn split_multi_byte_characters_are_joined() {
+        let line = "data: {\"content\":\"zażółć 🦀\"}\n".as_bytes();
+        // Split inside every character, including the four bytes of the emoji
+        for split in 1..line.len() {
+            let mut buffer = Vec::new();
+            buffer.extend_from_slice(&line[..split]);
+            let mut lines = take_complete_lines(&mut buffer);
+            buffer.extend_from_slice(&line[split..]);
+            lines.extend(take_complete_lines(&mut buffer));
+            assert_eq!(
+                lines,
+                ["data: {\"content\":\"zażółć 🦀\"}"],
+                "split at {}",
+                split
+            );
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn incomplete_lines_stay_in_the_buffer() {
+        let mut buffer = b"data: one\n\ndata: tw\xc3".to_vec();
+        assert_eq!(take_complete_lines(&mut buffer), ["data: one"]);
+        assert_eq!(buffer, b"data: tw\xc3");
+        buffer.extend_from_slice(b"\xb3\n");
+        assert_eq!(take_complete_lines(&mut buffer), ["data: twó"]);
+    }
+
+    #[test]
+    fn invalid_utf8_doesnt_fail() {
+        let mut buffer = b"data: \xff\xfe\n".to_vec();
+        assert_eq!(take_complete_lines(&mut buffer), ["data: \u{fffd}\u{fffd}"]);
+    }
+
+    #[test]
+    fn only_server_errors_are_retried() {
+        for status in [500, 502, 503, 504] {
+            assert!(is_retryable(reqwest::StatusCode::from_u16(status).unwrap()));
+        }
+        for status in [200, 400, 404, 429, 501] {
+            assert!(!is_retryable(
+                reqwest::StatusCode::from_u16(status).unwrap()
+            ));
+        }
+    }
+}
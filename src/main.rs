@@ -1,17 +1,44 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
+mod config;
+mod guard;
+mod llm;
+mod placeholder;
+mod state;
+mod tts;
+mod typing;
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, OnceLock, RwLock};
 
-use dotenv::dotenv;
-use log::{debug, error, info};
-use reqwest::{
-    header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE},
-    StatusCode,
+use config::Config;
+use dotenvy::dotenv;
+use guard::InjectionGuard;
+use llm::{
+    add_stop_sequences, chat_request_body, client, conversation_request, is_context_overflow,
+    request_completion, request_text_completion, stream_completion, typing_target,
+    with_time_budget, CompletionError, ErrorResponse, StreamedCompletion, BACKENDS, URL,
 };
+use placeholder::{Placeholder, RetryNotice};
+use reqwest::StatusCode;
 use serde::Deserialize;
-use serde_json::{json, Value};
-use teloxide::{prelude::*, utils::command::BotCommands};
+use serde_json::{Map, Value};
+use state::{AnswerFormat, ChatSettings, PromptEntry, PromptFormat, State, Turn};
+use teloxide::{
+    net::Download,
+    payloads::SendMessage,
+    prelude::*,
+    requests::JsonRequest,
+    types::{
+        Chat, ChatKind, ChatPublic, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, Me,
+        MessageKind, ParseMode, PublicChatKind, PublicChatSupergroup,
+    },
+    utils::{command::BotCommands, markdown},
+    ApiError, RequestError,
+};
+use tokio::sync::SemaphorePermit;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, warn, Span};
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+use typing::TypingIndicator;
 
 #[derive(Debug, Deserialize)]
 struct HealthResponse {
@@ -20,167 +47,3649 @@ struct HealthResponse {
     slots_processing: Option<u32>,
 }
 
-const URL: &str = "http://192.168.2.56:8080";
+// Telegram rejects messages longer than this
+const MESSAGE_LIMIT: usize = 4096;
+
+// Leave some room for the code block markup
+const RAW_RESPONSE_LIMIT: usize = 4000;
+
+// Keeps the request body small, nobody needs more than this to steer a 0.5B model
+const MAX_LOGIT_BIASES: usize = 16;
+
+// Same prompt every time, so results can be compared between tweaks
+const BENCH_PROMPT: &str = "Write a short story about a Raspberry Pi.";
+const BENCH_RUNS: usize = 3;
+
+// Don't let one hanging backend hold up the whole /diag report
+const DIAG_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Telegram rate limits edits, and the Pi takes a while per answer anyway
+const QUEUE_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+const STARTUP_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// The Pi can boot before the network is up, this gives it about a minute to get there
+const STARTUP_ATTEMPTS: u32 = 10;
+
+// Long prompts are cut off in /history, the start is usually enough to recognize them
+const HISTORY_PROMPT_LIMIT: usize = 200;
+// Leaves room for the header above the prompt in the /history browser
+const HISTORY_PAGE_LIMIT: usize = MESSAGE_LIMIT - 100;
+
+const DEFAULT_CLEAR_COUNT: usize = 10;
+
+// Bots can only delete messages for 48 hours after sending them
+const MAX_DELETE_AGE: chrono::TimeDelta = chrono::TimeDelta::hours(48);
+
+// Enough to recognize the question, the whole prompt is right there in the replied-to message
+const PROMPT_QUOTE_LIMIT: usize = 80;
+
+// Shorter code blocks are easy enough to copy from the message itself
+const CODE_FILE_MIN_CHARS: usize = 1000;
+
+// Keeps the /errors dump short, it's split into more messages if it still gets too long
+const ERROR_MESSAGE_LIMIT: usize = 150;
+const ERROR_DIAGNOSTICS_LIMIT: usize = 200;
+
+// Telegram only accepts reactions from a fixed list of emoji, which doesn't have ✅, ⏳ or ❌
+const HEALTH_OK_REACTION: &str = "👍";
+const HEALTH_LOADING_REACTION: &str = "😴";
+const HEALTH_ERROR_REACTION: &str = "👎";
+
+const DAILY_LIMIT_MESSAGE: &str = "The daily limit of requests is reached, try again tomorrow.";
+
+// The sampling parameters /compareparams accepts, the rest of the body isn't for users to replace
+const COMPARE_PARAMS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "min_p",
+    "typical_p",
+    "repeat_penalty",
+    "repeat_last_n",
+    "presence_penalty",
+    "frequency_penalty",
+    "seed",
+    "max_tokens",
+];
+
+const REPETITION_NOTE: &str = "(The model got stuck repeating itself.)";
+
+// The admins only need to hear about the server being down once, not for every failed request
+const OFFLINE_ALERT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// Dropped turns are summarized in batches, a summary request for every single one would double the load
+const SUMMARY_BATCH_TURNS: usize = 4;
+
+// Each prompt takes a while on the Pi, so a batch can't hold up the queue for too long
+const MAX_BATCH_PROMPTS: usize = 5;
+
+// Leaves enough of the small context for the instructions and the summary itself
+const SUMMARY_CHUNK_CHARS: usize = 2000;
+// Every part takes a while on the Pi, so longer texts are refused instead of taking an hour
+const MAX_SUMMARY_CHUNKS: usize = 8;
+// Summaries of summaries only get vaguer, after this the rest is cut off
+const MAX_SUMMARY_ROUNDS: usize = 2;
+const SUMMARY_PROMPT: &str = "Summarize this part of a longer text in a few sentences:\n\n";
+const COMBINE_SUMMARIES_PROMPT: &str =
+    "These are summaries of consecutive parts of one text. Combine them into a single short summary:\n\n";
+
+// Lets `/loglevel` change the filter while the bot is running
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    pretty_env_logger::init();
-    log::info!("Starting command bot...");
+    // Requests run concurrently, so every log line carries the span of the request it belongs to
+    let (filter, log_filter) = reload::Layer::new(EnvFilter::from_default_env());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    let _ = LOG_FILTER.set(log_filter);
+    info!("Starting command bot...");
 
     let bot = Bot::from_env();
+    let config = match Config::from_env() {
+        Ok(config) => Arc::new(config),
+        Err(problems) => {
+            error!(
+                "Invalid configuration, fix these in the environment or .env:\n{}",
+                problems.join("\n")
+            );
+            std::process::exit(1);
+        }
+    };
+    let state = Arc::new(State::new(config.max_concurrent_requests));
+    load_saved_state(None, &config, &state);
+    // The proxy URL can contain credentials, so it isn't logged
+    if config.proxy.is_some() {
+        info!("Connecting to the server through a proxy");
+    }
+    if let Err(e) = llm::init_client(config.proxy.as_deref()) {
+        error!("Couldn't set up the HTTP client: {}", e);
+        std::process::exit(1);
+    }
+
+    wait_for_model(&config).await;
+    if let Some(idle_unload_after) = config.idle_unload_after {
+        info!(
+            "Unloading the model after {}s without requests",
+            idle_unload_after.as_secs()
+        );
+        spawn_idle_unloader(Arc::clone(&config), Arc::clone(&state), idle_unload_after);
+    }
+    if let Some(interval) = config.keep_alive_interval {
+        info!(
+            "Pinging the server every {}s to keep the connection open",
+            interval.as_secs()
+        );
+        llm::spawn_keep_alive(interval, config.health_path.clone());
+    }
+    if let Some(load_max_tokens) = config.load_max_tokens {
+        info!(
+            "Lowering max_tokens to {} while {} or fewer slots are idle",
+            load_max_tokens, config.load_idle_slots
+        );
+        llm::spawn_load_monitor(
+            config.load_poll_interval,
+            config.health_path.clone(),
+            config.load_idle_slots,
+        );
+    }
 
     // Get the bot commands
-    bot.set_my_commands(Command::bot_commands()).await.unwrap();
+    retry_at_startup("setting the bot commands", || {
+        bot.set_my_commands(Command::bot_commands()).send()
+    })
+    .await;
+
+    let me = retry_at_startup("getting the bot's own user", || bot.get_me().send()).await;
+    info!(
+        "{} has started!",
+        me.user.username.as_deref().unwrap_or("The bot")
+    );
+
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .filter_map(new_command)
+                .endpoint(answer),
+        )
+        .branch(
+            Update::filter_message()
+                .filter_map(forwarded_prompt)
+                .endpoint(answer),
+        )
+        // Fixing a typo in a prompt runs it again, as a new request
+        .branch(
+            Update::filter_edited_message()
+                .filter_map(edited_command)
+                .endpoint(answer),
+        )
+        // Channel posts come without a sender, so they're only handled when explicitly enabled
+        .branch(
+            Update::filter_channel_post()
+                .filter(|config: Arc<RwLock<Config>>| config.read().unwrap().channel_replies)
+                .filter_map(parse_command)
+                .endpoint(answer),
+        )
+        .branch(Update::filter_my_chat_member().endpoint(my_chat_member_changed))
+        .branch(Update::filter_callback_query().endpoint(history_button_pressed));
+
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![
+            Arc::new(RwLock::new(Config::clone(&config))),
+            state
+        ])
+        // Teloxide handles updates from the same chat one at a time by default, which would make
+        // /health or /stop wait for the running generation. The model requests are limited separately.
+        .distribution_function(|_| None::<()>)
+        .default_handler(|_| async {})
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch()
+        .await;
+}
+
+/// Runs the Telegram request made by `request` until it succeeds, backing off between attempts.
+/// Exits after `STARTUP_ATTEMPTS` failures, the bot can't do anything without Telegram anyway.
+async fn retry_at_startup<T, Fut>(what: &str, request: impl Fn() -> Fut) -> T
+where
+    Fut: std::future::Future<Output = Result<T, RequestError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Ok(result) => return result,
+            Err(e) if attempt + 1 >= STARTUP_ATTEMPTS => {
+                error!(
+                    "Giving up on {} after {} attempts: {}",
+                    what, STARTUP_ATTEMPTS, e
+                );
+                std::process::exit(1);
+            }
+            Err(e) => {
+                let delay = llm::retry_delay(attempt, llm::jitter());
+                warn!(
+                    "Failed {}, retrying in {}ms ({}/{}): {}",
+                    what,
+                    delay.as_millis(),
+                    attempt + 1,
+                    STARTUP_ATTEMPTS - 1,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+        attempt += 1;
+    }
+}
+
+/// Polls the health check until the model is loaded, so the first requests don't just fail.
+/// Gives up with a warning after the startup timeout, the server might still come up later.
+async fn wait_for_model(config: &Config) {
+    let timeout = config.startup_timeout;
+    let start = std::time::Instant::now();
+    loop {
+        match client()
+            .get(format!("{}{}", URL, config.health_path))
+            .send()
+            .await
+        {
+            Ok(response) if response.status() == StatusCode::OK => {
+                info!("Model is ready after {}s", start.elapsed().as_secs());
+                return;
+            }
+            Ok(response) => info!(
+                "Waiting for the model to load (status {})...",
+                response.status()
+            ),
+            Err(e) => info!("Waiting for the server to come up: {}", e),
+        }
+
+        if start.elapsed() >= timeout {
+            warn!(
+                "Model still isn't ready after {}s, starting anyway",
+                timeout.as_secs()
+            );
+            return;
+        }
+        tokio::time::sleep(STARTUP_POLL_INTERVAL).await;
+    }
+}
+
+#[derive(BotCommands, Clone, Hash)]
+#[command(
+    rename_rule = "lowercase",
+    description = "This bot is 100% hosted on a 512MB Raspberry Pi Zero 2 W. Expect low performance and low quality.\n\nThese commands are supported:"
+)]
+enum Command {
+    #[command(description = "LLM request")]
+    Qwen(String),
+    #[command(description = "LLM request answered in a single line, for quick questions")]
+    Line(String),
+    #[command(description = "LLM request that also shows the model's reasoning, if it has any")]
+    Think(String),
+    #[command(description = "Ask the model something fun, for when you don't know what to ask")]
+    Random,
+    #[command(description = "Prints this help")]
+    Help,
+    #[command(description = "Health check")]
+    Health,
+    #[command(description = "LLM request answered with a voice message")]
+    Say(String),
+    #[command(description = "Ask several questions at once, one per line")]
+    Batch(String),
+    #[command(description = "Raw text completion, without the chat template")]
+    Complete(String),
+    #[command(
+        description = "Summarize a text that's too long for the model, reply to a message or a text file with it"
+    )]
+    LongSummarize(String),
+    #[command(description = "Set token biases, e.g. /bias 1234:-100 5678:5 (or /bias clear)")]
+    Bias(String),
+    #[command(
+        description = "Set how many messages the bot remembers in this chat, e.g. /memory 4 (or /memory off)"
+    )]
+    Memory(String),
+    #[command(
+        description = "Turn the typing indicator off (or on) in this chat, e.g. /typing off"
+    )]
+    Typing(String),
+    #[command(
+        description = "Keep a summary of messages that no longer fit in the memory, e.g. /summary on"
+    )]
+    Summary(String),
+    #[command(
+        description = "Switch between the chat and instruct prompt formats, e.g. /mode instruct"
+    )]
+    Mode(String),
+    #[command(
+        description = "Ask for answers as bullet points or prose, e.g. /format bullets (or /format off)"
+    )]
+    Format(String),
+    #[command(
+        description = "Pick the model used in this chat, e.g. /model small (or /model default)"
+    )]
+    Model(String),
+    #[command(description = "List the model aliases")]
+    Models,
+    #[command(
+        description = "Pick a persona for this chat, e.g. /persona pirate (or /persona off)"
+    )]
+    Persona(String),
+    #[command(description = "List the personas")]
+    Personas,
+    #[command(
+        description = "Set the maximum answer length in tokens, e.g. /maxtokens 128 (or /maxtokens default)"
+    )]
+    MaxTokens(String),
+    #[command(
+        description = "Cut answers in this chat off after this many characters, e.g. /maxlen 500 (or /maxlen off)"
+    )]
+    MaxLen(String),
+    #[command(
+        description = "Set how many seconds an answer may take in this chat, e.g. /budget 60 (or /budget off)"
+    )]
+    Budget(String),
+    #[command(
+        description = "Search your past prompts, e.g. /history weather (or browse them with just /history)"
+    )]
+    History(String),
+    #[command(description = "Delete the bot's latest messages in this chat, e.g. /clear 5")]
+    Clear(String),
+    #[command(description = "Pin the answer of the bot you reply to")]
+    Pin,
+    #[command(description = "Unpin the message you reply to, or the latest pinned one")]
+    Unpin,
+    #[command(description = "Show the settings of this chat")]
+    Settings,
+    #[command(description = "Show the sampling parameters sent to the model for this chat")]
+    Params,
+    #[command(
+        description = "Answer a prompt with two sets of parameters, e.g. /compareparams temperature=0.2 | temperature=0.8 | Write a haiku"
+    )]
+    CompareParams(String),
+    #[command(
+        description = "Delete your prompt history and the settings of your private chat with the bot"
+    )]
+    ForgetMe,
+    #[command(description = "Stop the running requests in this chat")]
+    Stop,
+    #[command(description = "Show the bot, server and model versions")]
+    Version,
+    // Sent by Telegram when someone opens the bot, no need to list it
+    #[command(description = "off")]
+    Start,
+    // Admin only, hidden from the help message
+    #[command(description = "off")]
+    Raw(String),
+    #[command(description = "off")]
+    Errors,
+    #[command(description = "off")]
+    Pause,
+    #[command(description = "off")]
+    Resume,
+    #[command(description = "off")]
+    Diag,
+    #[command(description = "off")]
+    Diagnose,
+    #[command(description = "off")]
+    Bench,
+    #[command(description = "off")]
+    Metrics,
+    #[command(description = "off")]
+    Echo(String),
+    #[command(description = "off")]
+    Threads(String),
+    #[command(description = "off")]
+    Reload,
+    #[command(description = "off")]
+    LogLevel(String),
+}
+
+/// Parses the command in the message's text, or in its caption so commands can be sent along with a photo.
+/// Teloxide's `filter_command` only looks at the text.
+fn parse_command(msg: Message, me: Me, config: Arc<RwLock<Config>>) -> Option<Command> {
+    // Whoever forwards a message didn't write the command in it, so it isn't run on their behalf
+    if msg.forward().is_some() {
+        return None;
+    }
+    let text = msg.text().or_else(|| msg.caption())?;
+    // Teloxide only splits the command from its arguments at a space, so `/batch` followed by
+    // a new line wouldn't be recognized
+    let text = match text.find(char::is_whitespace) {
+        Some(end) if !text[end..].starts_with(' ') => format!("{} {}", &text[..end], &text[end..]),
+        _ => text.to_string(),
+    };
+    let text = text.as_str();
+    let config = config.read().unwrap();
+    // The built-in names always win, so an alias can't take over a real command
+    let mut cmd = Command::parse(text, me.username())
+        .ok()
+        .or_else(|| Command::parse(&resolve_command_alias(&config, text)?, me.username()).ok())?;
+    if let Some(prompt) = cmd.chat_prompt_mut() {
+        strip_bot_mention(prompt, me.username());
+    }
+    Some(cmd)
+}
+
+/// Removes a mention of the bot from the start of `prompt`, as in `/qwen @mybot hello`. teloxide
+/// already strips the one glued to the command (`/qwen@mybot hello`), but not one after a space.
+fn strip_bot_mention(prompt: &mut String, username: &str) {
+    let Some(rest) = prompt.trim_start().strip_prefix('@') else {
+        return;
+    };
+    let Some(rest) = rest
+        .get(..username.len())
+        .filter(|mention| mention.eq_ignore_ascii_case(username))
+        .map(|_| &rest[username.len()..])
+    else {
+        return;
+    };
+    // `@mybot2` is someone else
+    if rest.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+        return;
+    }
+    *prompt = rest.trim_start().to_string();
+}
+
+/// Parses a new message's command and remembers it, so editing the message later only runs it
+/// again when the command changed.
+fn new_command(
+    msg: Message,
+    me: Me,
+    config: Arc<RwLock<Config>>,
+    state: Arc<State>,
+) -> Option<Command> {
+    let cmd = parse_command(msg.clone(), me, config)?;
+    if cmd.reruns_on_edit() {
+        state.swap_message_command(msg.chat.id, msg.id, command_hash(&cmd));
+    }
+    Some(cmd)
+}
+
+/// Parses the command of an edited message, `None` if it isn't one to run again or the edit
+/// didn't change it, e.g. when only the formatting was fixed.
+fn edited_command(
+    msg: Message,
+    me: Me,
+    config: Arc<RwLock<Config>>,
+    state: Arc<State>,
+) -> Option<Command> {
+    let cmd = parse_command(msg.clone(), me, config)?;
+    if !cmd.reruns_on_edit() {
+        return None;
+    }
+    let hash = command_hash(&cmd);
+    let previous = state.swap_message_command(msg.chat.id, msg.id, hash);
+    (previous != Some(hash)).then_some(cmd)
+}
+
+fn command_hash(cmd: &Command) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    cmd.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Turns a message forwarded to the bot in a private chat into a `/qwen` prompt, if
+/// `FORWARDED_PROMPTS` is on. In groups people forward things to each other, not to the bot.
+fn forwarded_prompt(msg: Message, config: Arc<RwLock<Config>>) -> Option<Command> {
+    if !config.read().unwrap().forwarded_prompts || !msg.chat.is_private() {
+        return None;
+    }
+    msg.forward()?;
+    let text = msg.text().or_else(|| msg.caption())?;
+    Some(Command::Qwen(text.to_string()))
+}
+
+/// Swaps a configured alias at the start of `text` for the command it stands for,
+/// e.g. `/ask@bot hi` becomes `/qwen@bot hi`. `None` if `text` doesn't start with an alias.
+fn resolve_command_alias(config: &Config, text: &str) -> Option<String> {
+    let rest = text.strip_prefix('/')?;
+    let end = rest
+        .find(|c: char| c.is_whitespace() || c == '@')
+        .unwrap_or(rest.len());
+    let (name, tail) = rest.split_at(end);
+    let (_, command) = config
+        .command_aliases
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))?;
+    Some(format!("/{}{}", command, tail))
+}
+
+impl Command {
+    fn is_admin_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Raw(_)
+                | Command::Errors
+                | Command::Pause
+                | Command::Resume
+                | Command::Diag
+                | Command::Diagnose
+                | Command::Bench
+                | Command::Metrics
+                | Command::Echo(_)
+                | Command::Threads(_)
+                | Command::Reload
+                | Command::LogLevel(_)
+        )
+    }
+
+    /// The prompt of commands that send it to the model as a chat message, which is where
+    /// `INJECTION_GUARD` applies.
+    fn chat_prompt_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Command::Qwen(prompt)
+            | Command::Line(prompt)
+            | Command::Think(prompt)
+            | Command::Say(prompt)
+            | Command::Batch(prompt) => Some(prompt),
+            _ => None,
+        }
+    }
+
+    /// Commands that run again when their message is edited. Only a single prompt or a cheap
+    /// lookup, editing a `/batch` or an admin command over and over shouldn't repeat it.
+    fn reruns_on_edit(&self) -> bool {
+        matches!(
+            self,
+            Command::Qwen(_)
+                | Command::Line(_)
+                | Command::Think(_)
+                | Command::Say(_)
+                | Command::Complete(_)
+                | Command::Help
+                | Command::Health
+        )
+    }
+
+    /// Commands that send a request to the model, these are refused while the bot is paused.
+    fn uses_model(&self) -> bool {
+        matches!(
+            self,
+            Command::Qwen(_)
+                | Command::Line(_)
+                | Command::Think(_)
+                | Command::Batch(_)
+                | Command::Say(_)
+                | Command::Complete(_)
+                | Command::LongSummarize(_)
+                | Command::Raw(_)
+                | Command::Bench
+                | Command::CompareParams(_)
+                | Command::Random
+        )
+    }
+}
+
+/// Parses space separated `token:bias` pairs, e.g. `1234:-100 5678:5`.
+fn parse_logit_bias(input: &str) -> Result<Vec<(u32, f32)>, String> {
+    let pairs: Vec<&str> = input.split_whitespace().collect();
+    if pairs.len() > MAX_LOGIT_BIASES {
+        return Err(format!(
+            "At most {} token biases can be set.",
+            MAX_LOGIT_BIASES
+        ));
+    }
+
+    pairs
+        .into_iter()
+        .map(|pair| {
+            let (token, bias) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid pair \"{}\", expected token:bias.", pair))?;
+            let token = token
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid token id: {}", token))?;
+            let bias = bias
+                .parse::<f32>()
+                .ok()
+                .filter(|bias| (-100.0..=100.0).contains(bias))
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid bias {}, expected a number between -100 and 100.",
+                        bias
+                    )
+                })?;
+            Ok((token, bias))
+        })
+        .collect()
+}
+
+/// Formats token biases the same way `/bias` accepts them.
+fn format_logit_bias(logit_bias: &[(u32, f32)]) -> String {
+    logit_bias
+        .iter()
+        .map(|(token, bias)| format!("{}:{}", token, bias))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Pretty-prints the response body (if it's valid JSON) into a code block that fits in one message.
+fn format_raw_response(body: &str) -> String {
+    let pretty = match serde_json::from_str::<Value>(body) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap_or_else(|_| body.to_string()),
+        Err(_) => body.to_string(),
+    };
+
+    let pretty = if pretty.chars().count() > RAW_RESPONSE_LIMIT {
+        let truncated: String = pretty.chars().take(RAW_RESPONSE_LIMIT).collect();
+        format!("{}\n... (truncated)", truncated)
+    } else {
+        pretty
+    };
+
+    markdown::code_block_with_lang(&pretty, "json")
+}
+
+/// Waits until a request can be sent to the model, `None` if `cancel` fires first.
+/// If the request has to wait, the user gets the configured queue message.
+#[instrument(skip_all)]
+async fn wait_for_turn<'a>(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &'a State,
+    cancel: &CancellationToken,
+) -> Option<SemaphorePermit<'a>> {
+    if let Some(permit) = state.try_acquire_completion_permit() {
+        wake_model(bot, msg, config, state).await;
+        return Some(permit);
+    }
+
+    let ticket = state.enter_queue();
+    info!(
+        "No free request slot in {}, queued at position {}",
+        msg.chat.id,
+        ticket.position()
+    );
+    let mut queue_message = None;
+    if let Some(template) = &config.queue_message {
+        let text = format_queue_message(config, state, template, ticket.position());
+        match reply(bot, msg, config, text.clone()).await {
+            Ok(sent) => {
+                state.track_sent_message(&sent);
+                queue_message = Some((sent, template, text));
+            }
+            Err(e) => warn!("Couldn't send the queue message: {}", e),
+        }
+    }
+
+    let acquire = state.acquire_completion_permit(cancel);
+    tokio::pin!(acquire);
+    let permit = loop {
+        tokio::select! {
+            permit = &mut acquire => break permit?,
+            _ = tokio::time::sleep(QUEUE_UPDATE_INTERVAL), if queue_message.is_some() => {
+                // Keeps the position and the estimate current as the queue drains
+                let Some((sent, template, last_text)) = &mut queue_message else {
+                    continue;
+                };
+                let text = format_queue_message(config, state, template, ticket.position());
+                if text == *last_text {
+                    continue;
+                }
+                match bot.edit_message_text(sent.chat.id, sent.id, text.clone()).await {
+                    Ok(_) => *last_text = text,
+                    Err(e) => debug!("Couldn't update the queue message: {}", e),
+                }
+            }
+        }
+    };
+    wake_model(bot, msg, config, state).await;
+    Some(permit)
+}
+
+/// The queue message for `position`, with an estimate of the wait once there are generation
+/// times to go by. Every request ahead takes about the average, spread over the request slots.
+fn format_queue_message(config: &Config, state: &State, template: &str, position: usize) -> String {
+    let mut message = template.replace("{position}", &position.to_string());
+    if let Some(average) = state.average_generation_time() {
+        let wait = average * position as u32 / config.max_concurrent_requests.max(1) as u32;
+        message.push_str(&format!("\n~{}s until your turn.", wait.as_secs().max(1)));
+    }
+    message
+}
+
+/// Runs the wake command if the model was unloaded for being idle, and waits for it to load.
+async fn wake_model(bot: &Bot, msg: &Message, config: &Config, state: &State) {
+    state.touch();
+    if !state.take_model_unloaded() {
+        return;
+    }
+    let Some(wake_command) = &config.wake_command else {
+        return;
+    };
+
+    info!("Waking up the model");
+    match reply(bot, msg, config, "Waking up the model...").await {
+        Ok(sent) => state.track_sent_message(&sent),
+        Err(e) => warn!("Couldn't send the wake up message: {}", e),
+    }
+    run_command(wake_command).await;
+    wait_for_model(config).await;
+}
+
+/// Checks every now and then whether the bot has been idle long enough to unload the model.
+fn spawn_idle_unloader(
+    config: Arc<Config>,
+    state: Arc<State>,
+    idle_unload_after: std::time::Duration,
+) {
+    tokio::spawn(async move {
+        let check_interval = (idle_unload_after / 4).min(std::time::Duration::from_secs(60));
+        let mut unloaded = false;
+        loop {
+            tokio::time::sleep(check_interval).await;
+            let idle = state.idle_time() >= idle_unload_after
+                // Requests that are still running or queued count as activity too
+                && state.free_completion_permits() == config.max_concurrent_requests;
+            if !idle {
+                unloaded = false;
+                continue;
+            }
+            if unloaded {
+                continue;
+            }
+            let Some(unload_command) = &config.unload_command else {
+                return;
+            };
+            info!(
+                "Idle for {}s, unloading the model",
+                state.idle_time().as_secs()
+            );
+            run_command(unload_command).await;
+            state.set_model_unloaded(true);
+            unloaded = true;
+        }
+    });
+}
+
+async fn run_command(command: &str) {
+    match tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .await
+    {
+        Ok(status) if status.success() => debug!("Ran {}", command),
+        Ok(status) => warn!("{} exited with {}", command, status),
+        Err(e) => error!("Couldn't run {}: {}", command, e),
+    }
+}
+
+/// Reads the daily request count and the welcomed users from their files, for the features that
+/// are on. With the `old` config from before a `/reload`, only the files that weren't read yet
+/// are, so turning a feature on or moving its file doesn't start it over and overwrite the file.
+fn load_saved_state(old: Option<&Config>, config: &Config, state: &State) {
+    let daily_limit_started = old.is_none_or(|old| {
+        old.daily_request_limit.is_none() || old.daily_limit_file != config.daily_limit_file
+    });
+    if config.daily_request_limit.is_some() && daily_limit_started {
+        if let Err(e) = state.load_daily_requests(&config.daily_limit_file) {
+            warn!(
+                "Couldn't read the daily request count from {}, starting from 0: {}",
+                config.daily_limit_file, e
+            );
+        }
+    }
+    let welcome_started = old.is_none_or(|old| {
+        old.welcome_notice.is_none() || old.welcomed_file != config.welcomed_file
+    });
+    if config.welcome_notice.is_some() && welcome_started {
+        if let Err(e) = state.load_welcomed(&config.welcomed_file) {
+            warn!(
+                "Couldn't read the welcomed users from {}, everyone gets the notice again: {}",
+                config.welcomed_file, e
+            );
+        }
+    }
+}
+
+/// Counts a model request towards `DAILY_REQUEST_LIMIT`, so `/batch` and `/longsummarize` cost
+/// as much as the requests they send. Admins aren't counted. Returns the message to show instead
+/// once the limit is reached.
+fn count_daily_request(config: &Config, msg: &Message, state: &State) -> Result<(), String> {
+    let Some(limit) = config.daily_request_limit else {
+        return Ok(());
+    };
+    if config.is_admin(msg) {
+        return Ok(());
+    }
+    if !state.count_daily_request(config.today(), limit) {
+        info!("Daily limit of {} requests reached", limit);
+        return Err(DAILY_LIMIT_MESSAGE.to_string());
+    }
+    if let Err(e) = state.save_daily_requests(&config.daily_limit_file) {
+        warn!("Couldn't save the daily request count: {}", e);
+    }
+    Ok(())
+}
+
+/// Logs and records a failed request, returning the message to show the user.
+fn report_completion_error(
+    bot: &Bot,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    e: CompletionError,
+) -> String {
+    if let CompletionError::Cancelled = e {
+        info!("Request #{} was cancelled", request_id);
+    } else {
+        error!("{}", e);
+        record_server_error(config, state, request_id, e.to_string());
+    }
+    if e.is_server_offline() {
+        alert_admins_offline(bot, config, state);
+        return config.server_offline_message.clone();
+    }
+    e.user_message().to_string()
+}
+
+/// Tells the admins in the background that the server can't be reached, if `OFFLINE_ALERTS` is on.
+fn alert_admins_offline(bot: &Bot, config: &Config, state: &State) {
+    if !config.offline_alerts || !state.take_offline_alert(OFFLINE_ALERT_INTERVAL) {
+        return;
+    }
+    warn!("The server is offline, alerting the admins");
+    for &admin in &config.admin_ids {
+        let bot = bot.clone();
+        tokio::spawn(async move {
+            let alert = format!(
+                "The model server at {} is offline, requests can't connect to it.",
+                URL
+            );
+            if let Err(e) = bot.send_message(admin, alert).await {
+                warn!(
+                    "Couldn't alert admin {} about the offline server: {}",
+                    admin, e
+                );
+            }
+        });
+    }
+}
+
+/// Records an error the server caused. With `ERROR_DIAGNOSTICS` on, the state of the server's
+/// slots is fetched in the background and added to it, it's only shown in the admin-only `/errors`.
+fn record_server_error(config: &Config, state: &State, request_id: u64, message: String) {
+    let diagnostics = state.record_error(request_id, message);
+    if config.error_diagnostics {
+        let config = config.clone();
+        tokio::spawn(async move {
+            let _ = diagnostics.set(llm::server_diagnostics(&config).await);
+        });
+    }
+}
+
+/// Runs a `/qwen` request and returns the answer, or the error message to show instead.
+/// `stop` is added to the stop sequences of the request, e.g. a line break for `/line`.
+#[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+async fn ask_qwen(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    prompt: String,
+    stop: &[&str],
+    retry_notice: Option<RetryNotice>,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    count_daily_request(config, msg, state)?;
+    let Some(_permit) = wait_for_turn(bot, msg, config, state, cancel).await else {
+        return Err(report_completion_error(
+            bot,
+            config,
+            state,
+            request_id,
+            CompletionError::Cancelled,
+        ));
+    };
+
+    let started = std::time::Instant::now();
+    let settings = state.chat_settings(msg.chat.id);
+    let history_turns = config.history_turns(&settings);
+    let request = with_time_budget(cancel, config.time_budget(&settings));
+    // Also ends the time budget timer once we're done
+    let _request_guard = request.clone().drop_guard();
+    let mut history = state.history(msg.chat.id, history_turns);
+    let summary = state.history_summary(msg.chat.id);
+    let request_for = |history: &[Turn]| {
+        let (path, mut body) =
+            conversation_request(config, &prompt, summary.as_deref(), history, &settings);
+        add_stop_sequences(&mut body, stop);
+        (path, body)
+    };
+    let (path, body) = request_for(&history);
+    let mut completion = stream_completion(
+        typing_target(bot, msg, &settings),
+        config,
+        path,
+        body,
+        retry_notice.as_ref(),
+        &request,
+    )
+    .await;
+    // llama.cpp sometimes sends a broken chunk under load, which is usually gone on the next try
+    if let Err(CompletionError::InvalidChunk(e)) = &completion {
+        warn!(
+            "Couldn't parse the stream of request #{}, retrying once: {}",
+            request_id, e
+        );
+        let (path, body) = request_for(&history);
+        completion = stream_completion(
+            typing_target(bot, msg, &settings),
+            config,
+            path,
+            body,
+            retry_notice.as_ref(),
+            &request,
+        )
+        .await;
+    }
+
+    // Long conversations can outgrow the small context, so retry once with less history
+    let mut trimmed_history = false;
+    let mut dropped_turns = Vec::new();
+    if matches!(&completion, Ok(StreamedCompletion::Rejected(body)) if is_context_overflow(body))
+        && !history.is_empty()
+    {
+        let dropped = history.len().div_ceil(2);
+        warn!(
+            "Request #{} exceeded the context size, dropping {} of {} history turns",
+            request_id,
+            dropped,
+            history.len()
+        );
+        dropped_turns = state.drop_oldest_turns(msg.chat.id, dropped);
+        history = state.history(msg.chat.id, history_turns);
+        let (path, body) = request_for(&history);
+        completion = stream_completion(
+            typing_target(bot, msg, &settings),
+            config,
+            path,
+            body,
+            retry_notice.as_ref(),
+            &request,
+        )
+        .await;
+        trimmed_history = true;
+    }
+
+    let mut notes = Vec::new();
+    let completion = match completion {
+        // Cancelled before the server started answering, there's nothing to show yet
+        Err(CompletionError::Cancelled) => StreamedCompletion::Stopped(String::new()),
+        completion => {
+            completion.map_err(|e| report_completion_error(bot, config, state, request_id, e))?
+        }
+    };
+    let response = match completion {
+        StreamedCompletion::Finished(response) => {
+            state.record_generation_time(started.elapsed());
+            response
+        }
+        // Only the time budget cancels `request` without cancelling `cancel`
+        StreamedCompletion::Stopped(response) if cancel.is_cancelled() => {
+            info!(
+                "Request #{} was stopped after {} characters",
+                request_id,
+                response.len()
+            );
+            notes.push(if state.is_paused() {
+                "(Stopped because the bot was paused for maintenance.)"
+            } else {
+                "(Stopped.)"
+            });
+            response
+        }
+        StreamedCompletion::Stopped(response) => {
+            warn!(
+                "Request #{} ran out of its time budget after {} characters",
+                request_id,
+                response.len()
+            );
+            notes.push("(Stopped early because the answer was taking too long.)");
+            response
+        }
+        StreamedCompletion::Rejected(body) => {
+            error!("Server rejected the request: {}", body);
+            record_server_error(
+                config,
+                state,
+                request_id,
+                format!("Server rejected the request: {}", body),
+            );
+            return Err(match ErrorResponse::parse(&body) {
+                Some(rejection) => rejection.user_message(),
+                None => "The server rejected the request.".to_string(),
+            });
+        }
+    };
+    // The tiny model sometimes just ends right away, that's not the server's fault
+    if response.trim().is_empty() && notes.is_empty() {
+        warn!("Request #{} got an empty answer", request_id);
+        return Err(config.empty_answer_message.clone());
+    }
+
+    info!("Response: {}", config.loggable(&response));
+    let response = match trim_repetition(&response, config.repetition_limit) {
+        Some(trimmed) => {
+            warn!("Request #{} got stuck repeating itself", request_id);
+            notes.push(REPETITION_NOTE);
+            trimmed
+        }
+        None => response,
+    };
+    if !response.trim().is_empty() {
+        dropped_turns.extend(state.push_turn(
+            msg.chat.id,
+            Turn {
+                prompt,
+                // The reasoning would only fill up the small context
+                response: split_reasoning(&response).1,
+            },
+            history_turns,
+        ));
+    }
+    if settings.summarize_history && !dropped_turns.is_empty() {
+        summarize_dropped_turns(bot, msg, config, state, request_id, dropped_turns, cancel).await;
+    }
+
+    if trimmed_history {
+        notes.push(
+            "(Older messages were dropped from the conversation to fit the model's context.)",
+        );
+    }
+    if notes.is_empty() {
+        Ok(response)
+    } else if response.trim().is_empty() {
+        Ok(notes.join("\n"))
+    } else {
+        Ok(format!("{}\n\n{}", response, notes.join("\n")))
+    }
+}
+
+fn format_prompt_format(prompt_format: PromptFormat) -> &'static str {
+    match prompt_format {
+        PromptFormat::Chat => "chat",
+        PromptFormat::Instruct => "instruct",
+    }
+}
+
+fn format_memory(config: &Config, settings: &ChatSettings) -> String {
+    if config.history_enabled(settings) {
+        format!("{} messages", config.history_turns(settings))
+    } else {
+        "off".to_string()
+    }
+}
+
+fn format_answer_format(answer_format: Option<AnswerFormat>) -> &'static str {
+    match answer_format {
+        Some(AnswerFormat::Bullets) => "bullets",
+        Some(AnswerFormat::Prose) => "prose",
+        None => "up to the model",
+    }
+}
+
+/// The chat's model, with the id it resolves to if it's an alias.
+fn format_model(config: &Config, settings: &ChatSettings) -> String {
+    let name = settings.model.as_deref().unwrap_or(&config.model);
+    let id = config.resolve_model(name);
+    if id == name {
+        name.to_string()
+    } else {
+        format!("{} ({})", name, id)
+    }
+}
+
+fn format_time_budget(budget: Option<std::time::Duration>) -> String {
+    match budget {
+        Some(budget) => format!("{} seconds", budget.as_secs()),
+        None => "none".to_string(),
+    }
+}
+
+/// A short quote of the prompt to put above the answer in groups, so the exchange still makes sense
+/// when the reply gets separated from the question. `None` if it's disabled or not a group.
+fn prompt_quote(config: &Config, msg: &Message, prompt: &str) -> Option<String> {
+    if !config.quote_prompts || !(msg.chat.is_group() || msg.chat.is_supergroup()) {
+        return None;
+    }
+    Some(quote_prompt(prompt))
+}
+
+/// The start of `prompt` as a quote to put above its answer.
+fn quote_prompt(prompt: &str) -> String {
+    let prompt = prompt.trim();
+    let mut quote: String = prompt.chars().take(PROMPT_QUOTE_LIMIT).collect();
+    if quote.len() < prompt.len() {
+        quote.push('…');
+    }
+    format!("» {}\n\n", quote)
+}
+
+/// Splits the `<think>` block off the front of an answer, returning the reasoning (if there is any)
+/// and the answer after it. Some chat templates already open the block in the prompt, so the
+/// answer may only have the closing tag. An unclosed block means the model never got past thinking.
+fn split_reasoning(response: &str) -> (Option<String>, String) {
+    let trimmed = response.trim_start();
+    let (opened, rest) = match trimmed.strip_prefix("<think>") {
+        Some(rest) => (true, rest),
+        None => (false, trimmed),
+    };
+    match rest.split_once("</think>") {
+        Some((reasoning, answer)) => (
+            Some(reasoning.trim().to_string()),
+            answer.trim_start().to_string(),
+        ),
+        None if opened => (Some(rest.trim().to_string()), String::new()),
+        None => (None, response.to_string()),
+    }
+}
+
+/// Turns the model's response into the answer to send: the reasoning goes separately and the
+/// chat's `/maxlen` applies. Returns the reasoning, if there was any, along with the answer.
+fn answer_text(response: &str, max_length: Option<usize>) -> (Option<String>, String) {
+    let (reasoning, mut answer) = split_reasoning(response);
+    if answer.trim().is_empty() {
+        answer = "(The model didn't finish thinking, so there's no answer.)".to_string();
+    }
+    (reasoning, truncate_response(answer, max_length))
+}
+
+/// Cuts `response` off where the 0.5B model started looping, which is when the same line or
+/// sentence comes more than `limit` times in a row. The first of the repeats is kept.
+/// Returns `None` if the model didn't get stuck (or `limit` is 0).
+fn trim_repetition(response: &str, limit: usize) -> Option<String> {
+    if limit == 0 {
+        return None;
+    }
+    let mut previous = "";
+    let mut repeats = 0;
+    let mut first_end = 0;
+    let mut start = 0;
+    for (i, c) in response.char_indices() {
+        if !matches!(c, '\n' | '.' | '!' | '?') {
+            continue;
+        }
+        let end = i + c.len_utf8();
+        // The dot in 3.14 or 1.2.3 doesn't end a sentence
+        if c == '.' && response[end..].starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        let segment = response[start..end].trim();
+        start = end;
+        // Ellipses and blank lines aren't the model repeating itself
+        if !segment.chars().any(char::is_alphanumeric) {
+            continue;
+        }
+        if segment == previous {
+            repeats += 1;
+            if repeats > limit {
+                return Some(response[..first_end].trim_end().to_string());
+            }
+        } else {
+            previous = segment;
+            repeats = 1;
+            first_end = end;
+        }
+    }
+    None
+}
+
+/// Cuts `response` off after `max_length` characters, saying so at the end.
+fn truncate_response(response: String, max_length: Option<usize>) -> String {
+    let Some(max_length) = max_length else {
+        return response;
+    };
+    match response.char_indices().nth(max_length) {
+        Some((end, _)) => format!("{}… (truncated)", response[..end].trim_end()),
+        None => response,
+    }
+}
+
+/// Quotes the model's reasoning line by line, so it doesn't get mixed up with the answer.
+fn format_reasoning(reasoning: &str) -> String {
+    let quoted = reasoning
+        .lines()
+        .map(|line| format!("» {}", line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("Reasoning:\n{}", quoted)
+}
+
+/// When the command replies to a message, puts that message's text in front of the prompt,
+/// so "explain this" works without pasting the text again.
+fn with_quoted_context(msg: &Message, prompt: String) -> String {
+    let Some(quoted) = msg
+        .reply_to_message()
+        .and_then(|quoted| quoted.text().or_else(|| quoted.caption()))
+    else {
+        return prompt;
+    };
+    format!(
+        "Referenced message:\n\"\"\"\n{}\n\"\"\"\n\n{}",
+        quoted, prompt
+    )
+}
+
+/// Runs a `/complete` request and returns the continuation, or the error message to show instead.
+#[instrument(skip_all)]
+async fn complete_text(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    prompt: &str,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    count_daily_request(config, msg, state)?;
+    let Some(_permit) = wait_for_turn(bot, msg, config, state, cancel).await else {
+        return Err(report_completion_error(
+            bot,
+            config,
+            state,
+            request_id,
+            CompletionError::Cancelled,
+        ));
+    };
+
+    let started = std::time::Instant::now();
+    let settings = state.chat_settings(msg.chat.id);
+    // llama.cpp sometimes cuts the body short under load, which is usually gone on the next try
+    let mut retried = false;
+    let parsed_response = loop {
+        let res_text = request_text_completion(bot, msg, config, prompt, &settings, cancel)
+            .await
+            .map_err(|e| report_completion_error(bot, config, state, request_id, e))?;
+        if let Some(rejection) = ErrorResponse::parse(&res_text) {
+            error!("Server rejected the request: {}", res_text);
+            record_server_error(
+                config,
+                state,
+                request_id,
+                format!("Server rejected the request: {}", res_text),
+            );
+            return Err(rejection.user_message());
+        }
+
+        match serde_json::from_str::<Value>(&res_text) {
+            Ok(parsed_response) => break parsed_response,
+            Err(e) if !retried => {
+                warn!(
+                    "Couldn't parse the response of request #{}, retrying once: {} (body: {})",
+                    request_id,
+                    e,
+                    config.loggable(&res_text)
+                );
+                retried = true;
+            }
+            Err(e) => {
+                error!(
+                    "Error parsing response: {} (body: {})",
+                    e,
+                    config.loggable(&res_text)
+                );
+                state.record_error(request_id, format!("Error parsing response: {}", e));
+                return Err("An error occurred while parsing the response.".to_string());
+            }
+        }
+    };
+
+    if parsed_response["choices"][0]["finish_reason"] == "length" {
+        warn!("Request #{} stopped at the max_tokens cap", request_id);
+    }
+    match parsed_response["choices"][0]["text"].as_str() {
+        Some(response) if response.trim().is_empty() => {
+            warn!("Request #{} got an empty answer", request_id);
+            Err(config.empty_answer_message.clone())
+        }
+        Some(response) => {
+            state.record_generation_time(started.elapsed());
+            info!("Response: {}", config.loggable(response));
+            match trim_repetition(response, config.repetition_limit) {
+                Some(trimmed) => {
+                    warn!("Request #{} got stuck repeating itself", request_id);
+                    Ok(format!("{}\n\n{}", trimmed, REPETITION_NOTE))
+                }
+                None => Ok(response.to_string()),
+            }
+        }
+        None => {
+            error!("Error parsing response: {:?}", parsed_response);
+            state.record_error(request_id, "Response is missing the completion text");
+            Err("An error occurred while parsing the response.".to_string())
+        }
+    }
+}
+
+/// Gets the text for `/longsummarize`: the one after the command, or the replied-to message's
+/// text or text file. The error is the message to show instead.
+async fn summary_input(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    args: &str,
+) -> Result<String, String> {
+    if !args.trim().is_empty() {
+        return Ok(args.to_string());
+    }
+    let Some(replied) = msg.reply_to_message() else {
+        return Err("Reply to a message or a text file with /longsummarize, or put the text after the command.".to_string());
+    };
+    let Some(document) = replied.document() else {
+        return replied
+            .text()
+            .or_else(|| replied.caption())
+            .map(String::from)
+            .ok_or_else(|| "That message has no text to summarize.".to_string());
+    };
+
+    let too_big = format!(
+        "That file is too big, the limit is {}KB.",
+        config.max_file_size / 1024
+    );
+    if document.file.size > config.max_file_size {
+        return Err(too_big);
+    }
+    let download = async {
+        let file = bot.get_file(&document.file.id).await?;
+        // The message's size can be missing or outdated, this is the one Telegram will send
+        if file.size > config.max_file_size {
+            return Ok(None);
+        }
+        let mut contents = Vec::new();
+        bot.download_file(&file.path, &mut contents).await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Some(contents))
+    };
+    let contents = download
+        .await
+        .map_err(|e| {
+            warn!("Couldn't download the file to summarize: {}", e);
+            "Couldn't download the file.".to_string()
+        })?
+        .ok_or(too_big)?;
+    String::from_utf8(contents).map_err(|_| "That isn't a text file.".to_string())
+}
+
+/// Summarizes a text that doesn't fit in the model's context by summarizing it part by part,
+/// then summarizing those summaries. Returns the summary, or the error message to show instead.
+#[instrument(skip_all)]
+async fn summarize_long_text(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    text: &str,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let chunks = split_text(text.trim(), SUMMARY_CHUNK_CHARS);
+    if chunks.len() > MAX_SUMMARY_CHUNKS {
+        return Err(format!(
+            "That's too long, I can summarize up to about {} characters.",
+            SUMMARY_CHUNK_CHARS * MAX_SUMMARY_CHUNKS
+        ));
+    }
+    // One permit for the whole job, so the parts don't get interleaved with other requests
+    let Some(_permit) = wait_for_turn(bot, msg, config, state, cancel).await else {
+        return Err(report_completion_error(
+            bot,
+            config,
+            state,
+            request_id,
+            CompletionError::Cancelled,
+        ));
+    };
+
+    let mut summaries = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        info!("Summarizing part {} of {}", i + 1, chunks.len());
+        let prompt = format!("{}{}", SUMMARY_PROMPT, chunk);
+        summaries
+            .push(one_off_completion(bot, msg, config, state, request_id, &prompt, cancel).await?);
+    }
+    if let [summary] = summaries.as_slice() {
+        return Ok(summary.clone());
+    }
+
+    for _ in 0..MAX_SUMMARY_ROUNDS {
+        let groups = split_text(&summaries.join("\n\n"), SUMMARY_CHUNK_CHARS);
+        if groups.len() == 1 {
+            break;
+        }
+        info!(
+            "Combining {} summaries in {} groups",
+            summaries.len(),
+            groups.len()
+        );
+        summaries.clear();
+        for group in groups {
+            let prompt = format!("{}{}", COMBINE_SUMMARIES_PROMPT, group);
+            summaries.push(
+                one_off_completion(bot, msg, config, state, request_id, &prompt, cancel).await?,
+            );
+        }
+    }
+    let summaries: String = summaries
+        .join("\n\n")
+        .chars()
+        .take(SUMMARY_CHUNK_CHARS)
+        .collect();
+    let prompt = format!("{}{}", COMBINE_SUMMARIES_PROMPT, summaries);
+    one_off_completion(bot, msg, config, state, request_id, &prompt, cancel).await
+}
+
+/// Queues turns dropped from the history for the chat's summary. Once `SUMMARY_BATCH_TURNS` are
+/// waiting, the model folds them into the summary, which makes that answer take a bit longer.
+/// The caller has to hold a completion permit.
+async fn summarize_dropped_turns(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    dropped: Vec<Turn>,
+    cancel: &CancellationToken,
+) {
+    if state.add_dropped_turns(msg.chat.id, dropped) < SUMMARY_BATCH_TURNS {
+        return;
+    }
+    let turns = state.take_dropped_turns(msg.chat.id);
+    info!(
+        "Summarizing {} dropped turns of {}",
+        turns.len(),
+        msg.chat.id
+    );
+
+    let mut prompt = "Summarize this conversation in a few sentences. Keep names, facts and decisions worth remembering, leave out small talk.\n\n".to_string();
+    if let Some(summary) = state.history_summary(msg.chat.id) {
+        prompt.push_str(&format!("Earlier: {}\n\n", summary));
+    }
+    for turn in &turns {
+        prompt.push_str(&format!(
+            "User: {}\nAssistant: {}\n",
+            turn.prompt, turn.response
+        ));
+    }
+    // Only the model carries over, a persona or answer format would color the summary
+    let settings = ChatSettings {
+        model: state.chat_settings(msg.chat.id).model,
+        ..ChatSettings::default()
+    };
+    let body = chat_request_body(config, &prompt, None, &[], &settings);
+    match one_off_body_completion(bot, msg, config, state, request_id, body, cancel).await {
+        Ok(summary) if !summary.trim().is_empty() => {
+            debug!("History summary: {}", config.loggable(&summary));
+            state.set_history_summary(msg.chat.id, summary.trim().to_string());
+        }
+        Ok(_) => warn!("The summary of {} came back empty", msg.chat.id),
+        // The turns are gone then, just like without a summary
+        Err(e) => warn!(
+            "Couldn't summarize the dropped turns of {}: {}",
+            msg.chat.id, e
+        ),
+    }
+}
+
+/// Sends `prompt` on its own, without the chat's history, and returns the whole answer.
+/// The caller has to hold a completion permit.
+async fn one_off_completion(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    prompt: &str,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let settings = state.chat_settings(msg.chat.id);
+    let body = chat_request_body(config, prompt, None, &[], &settings);
+    one_off_body_completion(bot, msg, config, state, request_id, body, cancel).await
+}
+
+/// Like `one_off_completion`, but with the request body already built.
+async fn one_off_body_completion(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    body: Value,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    count_daily_request(config, msg, state)?;
+    let typing = typing_target(bot, msg, &state.chat_settings(msg.chat.id));
+    match stream_completion(typing, config, &config.chat_path, body, None, cancel).await {
+        Ok(StreamedCompletion::Finished(response)) => Ok(split_reasoning(&response).1),
+        Ok(StreamedCompletion::Stopped(_)) | Err(CompletionError::Cancelled) => Err(
+            report_completion_error(bot, config, state, request_id, CompletionError::Cancelled),
+        ),
+        Ok(StreamedCompletion::Rejected(body)) => {
+            error!("Server rejected the request: {}", body);
+            record_server_error(
+                config,
+                state,
+                request_id,
+                format!("Server rejected the request: {}", body),
+            );
+            Err(match ErrorResponse::parse(&body) {
+                Some(rejection) => rejection.user_message(),
+                None => "The server rejected the request.".to_string(),
+            })
+        }
+        Err(e) => Err(report_completion_error(bot, config, state, request_id, e)),
+    }
+}
+
+/// Parses one side of `/compareparams`: either `name=value` pairs of `COMPARE_PARAMS` (values are
+/// JSON if they can be, text otherwise) or the name of a model with a `MODEL_PROFILES` entry.
+fn parse_param_set(config: &Config, input: &str) -> Result<Map<String, Value>, String> {
+    let input = input.trim();
+    if !input.contains('=') {
+        return config
+            .model_profiles
+            .get(config.resolve_model(input))
+            .and_then(Value::as_object)
+            .cloned()
+            .ok_or_else(|| format!("There's no parameter profile called \"{}\".", input));
+    }
+    input
+        .split([',', ' '])
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once('=')
+                .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+                .ok_or_else(|| format!("Invalid parameter \"{}\", expected name=value.", pair))?;
+            if !COMPARE_PARAMS.contains(&name) {
+                return Err(format!(
+                    "\"{}\" can't be compared, only these parameters can: {}.",
+                    name,
+                    COMPARE_PARAMS.join(", ")
+                ));
+            }
+            let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+            let value = match (name, value.as_u64()) {
+                // Same limit as /maxtokens
+                ("max_tokens", Some(max_tokens)) if max_tokens > 0 => {
+                    Value::from(max_tokens.min(u64::from(config.max_tokens_limit)))
+                }
+                ("max_tokens", _) => {
+                    return Err(format!(
+                        "Invalid max_tokens, expected a number between 1 and {}.",
+                        config.max_tokens_limit
+                    ))
+                }
+                _ => value,
+            };
+            Ok((name.to_string(), value))
+        })
+        .collect()
+}
+
+/// Runs `prompt` once with each parameter set, one after another so they don't slow each other
+/// down, and returns the labeled answers with how long each took.
+#[instrument(skip_all)]
+async fn compare_params(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    sets: &[(&str, Map<String, Value>)],
+    prompt: &str,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let request_id = state.next_request_id();
+    Span::current().record("request_id", request_id);
+    info!(
+        "Received comparison request #{}: {}",
+        request_id,
+        config.loggable(prompt)
+    );
+    let Some(_permit) = wait_for_turn(bot, msg, config, state, cancel).await else {
+        return Err(report_completion_error(
+            bot,
+            config,
+            state,
+            request_id,
+            CompletionError::Cancelled,
+        ));
+    };
+
+    let settings = state.chat_settings(msg.chat.id);
+    let mut results = Vec::with_capacity(sets.len());
+    for (label, params) in sets {
+        let mut body = chat_request_body(config, prompt, None, &[], &settings);
+        for (name, value) in params {
+            body[name] = value.clone();
+        }
+        let now = std::time::Instant::now();
+        let answer =
+            one_off_body_completion(bot, msg, config, state, request_id, body, cancel).await?;
+        let elapsed = now.elapsed();
+        info!(
+            "Comparison #{} with {} took {}ms",
+            request_id,
+            label,
+            elapsed.as_millis()
+        );
+        let answer = truncate_response(answer, settings.max_length);
+        results.push(format!(
+            "{} ({}ms):\n{}",
+            label,
+            elapsed.as_millis(),
+            answer.trim()
+        ));
+    }
+    Ok(results.join("\n\n"))
+}
+
+/// Fetches the server's `/props`, `None` (after logging why) if that fails.
+async fn fetch_props() -> Option<Value> {
+    let props = match client().get(format!("{}/props", URL)).send().await {
+        Ok(response) if response.status().is_success() => response.json::<Value>().await,
+        Ok(response) => {
+            warn!("Server returned {} for /props", response.status());
+            return None;
+        }
+        Err(e) => {
+            warn!("Error fetching server props: {}", e);
+            return None;
+        }
+    };
+    match props {
+        Ok(props) => Some(props),
+        Err(e) => {
+            warn!("Error parsing server props: {}", e);
+            None
+        }
+    }
+}
+
+/// Asks the server for its build and the loaded model, either can be missing on older builds.
+async fn fetch_server_info() -> (Option<String>, Option<String>) {
+    let Some(props) = fetch_props().await else {
+        return (None, None);
+    };
+
+    let version = props["build_info"].as_str().map(String::from);
+    let model = props["model_path"]
+        .as_str()
+        .or_else(|| props["default_generation_settings"]["model"].as_str())
+        .map(String::from);
+    (version, model)
+}
+
+/// Sends the benchmark prompt `BENCH_RUNS` times, one after another so the runs don't slow each other down,
+/// and returns the tokens per second of each run.
+#[instrument(skip_all)]
+async fn run_benchmark(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    request_id: u64,
+    cancel: &CancellationToken,
+) -> Result<Vec<f64>, String> {
+    let Some(_permit) = wait_for_turn(bot, msg, config, state, cancel).await else {
+        return Err(report_completion_error(
+            bot,
+            config,
+            state,
+            request_id,
+            CompletionError::Cancelled,
+        ));
+    };
+
+    // The chat's own settings (biases, max tokens) would make runs in different chats incomparable
+    let settings = ChatSettings::default();
+    let mut results = Vec::with_capacity(BENCH_RUNS);
+    for run in 1..=BENCH_RUNS {
+        let now = std::time::Instant::now();
+        let res_text = request_completion(bot, msg, config, BENCH_PROMPT, &settings, cancel)
+            .await
+            .map_err(|e| report_completion_error(bot, config, state, request_id, e))?;
+        let elapsed = now.elapsed();
+
+        let parsed_response = serde_json::from_str::<Value>(&res_text).map_err(|e| {
+            error!("Error parsing benchmark response: {}", e);
+            state.record_error(
+                request_id,
+                format!("Error parsing benchmark response: {}", e),
+            );
+            "An error occurred while parsing the response.".to_string()
+        })?;
+        // llama.cpp reports the generation speed itself, which leaves out the prompt processing
+        let tokens_per_second = match parsed_response["timings"]["predicted_per_second"].as_f64() {
+            Some(tokens_per_second) => tokens_per_second,
+            None => {
+                let tokens = parsed_response["usage"]["completion_tokens"]
+                    .as_f64()
+                    .unwrap_or(0.0);
+                tokens / elapsed.as_secs_f64()
+            }
+        };
+        info!(
+            "Benchmark #{} run {}/{}: {:.2} tokens/s in {}ms",
+            request_id,
+            run,
+            BENCH_RUNS,
+            tokens_per_second,
+            elapsed.as_millis()
+        );
+        results.push(tokens_per_second);
+    }
+    Ok(results)
+}
+
+fn format_benchmark(results: &[f64]) -> String {
+    let min = results.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = results.iter().copied().fold(0.0, f64::max);
+    let avg = results.iter().sum::<f64>() / results.len() as f64;
+    format!(
+        "Benchmark ({} runs): min {:.2}, avg {:.2}, max {:.2} tokens/s",
+        results.len(),
+        min,
+        avg,
+        max
+    )
+}
+
+/// How one backend answered the `/diag` health check.
+struct BackendCheck {
+    name: &'static str,
+    status: String,
+    latency: Option<std::time::Duration>,
+}
+
+async fn check_backend(name: &'static str, url: &'static str, health_path: &str) -> BackendCheck {
+    let now = std::time::Instant::now();
+    let response = tokio::time::timeout(
+        DIAG_TIMEOUT,
+        client().get(format!("{}{}", url, health_path)).send(),
+    )
+    .await;
+    let latency = now.elapsed();
+
+    let (status, latency) = match response {
+        Ok(Ok(response)) => {
+            let status = response.status();
+            let health = response.json::<HealthResponse>().await.ok();
+            let status = match health {
+                Some(health) => health.status,
+                None => status.to_string(),
+            };
+            (status, Some(latency))
+        }
+        Ok(Err(e)) => {
+            warn!("Backend {} ({}) is unreachable: {}", name, url, e);
+            ("UNREACHABLE".to_string(), None)
+        }
+        Err(_) => {
+            warn!("Backend {} ({}) timed out", name, url);
+            ("UNREACHABLE (timeout)".to_string(), None)
+        }
+    };
+    BackendCheck {
+        name,
+        status,
+        latency,
+    }
+}
+
+/// Formats the `/diag` results as a table, it's sent in a code block so the columns line up.
+fn format_backend_checks(checks: &[BackendCheck]) -> String {
+    let name_width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("backend".len());
+    let status_width = checks
+        .iter()
+        .map(|check| check.status.len())
+        .max()
+        .unwrap_or(0)
+        .max("status".len());
+
+    let mut table = format!(
+        "{:name_width$}  {:status_width$}  latency",
+        "backend", "status"
+    );
+    for check in checks {
+        let latency = match check.latency {
+            Some(latency) => format!("{}ms", latency.as_millis()),
+            None => "-".to_string(),
+        };
+        table.push_str(&format!(
+            "\n{:name_width$}  {:status_width$}  {}",
+            check.name, check.status, latency
+        ));
+    }
+    markdown::code_block(&table)
+}
+
+/// One line of the `/diagnose` report, `Err` holds why the check failed.
+struct DiagnoseCheck {
+    name: &'static str,
+    result: Result<String, String>,
+}
+
+/// Whether the server answers its health check at all, and whether it says the model is loaded.
+async fn check_server_health(config: &Config) -> [DiagnoseCheck; 2] {
+    let now = std::time::Instant::now();
+    let response = tokio::time::timeout(
+        DIAG_TIMEOUT,
+        client()
+            .get(format!("{}{}", URL, config.health_path))
+            .send(),
+    )
+    .await;
+    let latency = now.elapsed();
+
+    let response = match response {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            warn!("Diagnose: the server is unreachable: {}", e);
+            return [
+                DiagnoseCheck {
+                    name: "Server reachable",
+                    result: Err(e.to_string()),
+                },
+                DiagnoseCheck {
+                    name: "Model loaded",
+                    result: Err("the server is unreachable".to_string()),
+                },
+            ];
+        }
+        Err(_) => {
+            warn!("Diagnose: the server timed out");
+            return [
+                DiagnoseCheck {
+                    name: "Server reachable",
+                    result: Err(format!("no answer in {}s", DIAG_TIMEOUT.as_secs())),
+                },
+                DiagnoseCheck {
+                    name: "Model loaded",
+                    result: Err("the server is unreachable".to_string()),
+                },
+            ];
+        }
+    };
+
+    let status = response.status();
+    let health = response.json::<HealthResponse>().await.ok();
+    // "no slot available" still means the model is loaded, it's just busy
+    let model = match health {
+        Some(health) if health.status == "ok" || health.status == "no slot available" => {
+            Ok(health.status)
+        }
+        Some(health) => Err(health.status),
+        None => Err(format!("unexpected health check response ({})", status)),
+    };
+    [
+        DiagnoseCheck {
+            name: "Server reachable",
+            result: Ok(format!("{}ms", latency.as_millis())),
+        },
+        DiagnoseCheck {
+            name: "Model loaded",
+            result: model,
+        },
+    ]
+}
+
+/// Whether the server accepts the configured API keys, by listing its models with each of them.
+async fn check_api_key(config: &Config) -> DiagnoseCheck {
+    let name = "API key accepted";
+    if config.api_keys.is_empty() {
+        return DiagnoseCheck {
+            name,
+            result: Ok("no API key configured".to_string()),
+        };
+    }
+
+    let mut statuses = Vec::new();
+    for (i, key) in config.api_keys.iter().enumerate() {
+        // Keys are named by their position, their values never end up in a message
+        let label = format!("key {}/{}", i + 1, config.api_keys.len());
+        let Some(authorization) = Config::authorization(key) else {
+            return DiagnoseCheck {
+                name,
+                result: Err(format!("{} is invalid", label)),
+            };
+        };
+        let request = client()
+            .get(format!("{}/v1/models", URL))
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .send();
+        match tokio::time::timeout(DIAG_TIMEOUT, request).await {
+            Ok(Ok(response)) => match response.status() {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    return DiagnoseCheck {
+                        name,
+                        result: Err(format!(
+                            "{}: the server answered {}",
+                            label,
+                            response.status()
+                        )),
+                    };
+                }
+                status => statuses.push((label, status)),
+            },
+            Ok(Err(e)) => {
+                return DiagnoseCheck {
+                    name,
+                    result: Err(e.without_url().to_string()),
+                }
+            }
+            Err(_) => {
+                return DiagnoseCheck {
+                    name,
+                    result: Err(format!("no answer in {}s", DIAG_TIMEOUT.as_secs())),
+                }
+            }
+        }
+    }
+    let result = Ok(match statuses.as_slice() {
+        [(_, status)] => status.to_string(),
+        statuses => statuses
+            .iter()
+            .map(|(label, status)| format!("{}: {}", label, status))
+            .collect::<Vec<_>>()
+            .join(", "),
+    });
+    DiagnoseCheck { name, result }
+}
+
+/// Whether the daily request count can be saved, the only thing the bot writes to disk.
+fn check_daily_limit_file(config: &Config) -> DiagnoseCheck {
+    let name = "Daily count file writable";
+    let path = std::path::Path::new(&config.daily_limit_file);
+    // Opening for appending doesn't touch the saved count, a missing file is created and removed again
+    let existed = path.exists();
+    let result = match std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+    {
+        Ok(_) => {
+            if !existed {
+                let _ = std::fs::remove_file(path);
+            }
+            Ok(config.daily_limit_file.clone())
+        }
+        Err(e) => Err(format!("{}: {}", config.daily_limit_file, e)),
+    };
+    DiagnoseCheck { name, result }
+}
+
+/// Formats the `/diagnose` results, one check per line.
+fn format_diagnose_checks(checks: &[DiagnoseCheck]) -> String {
+    checks
+        .iter()
+        .map(|check| match &check.result {
+            Ok(detail) => format!("✅ {} ({})", check.name, detail),
+            Err(reason) => format!("❌ {}: {}", check.name, reason),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Deletes up to `count` of the bot's latest messages in the chat.
+/// Returns how many were deleted and how many couldn't be, usually because they're too old.
+async fn delete_sent_messages(
+    bot: &Bot,
+    state: &State,
+    chat_id: ChatId,
+    count: usize,
+) -> (usize, usize) {
+    let mut deleted = 0;
+    let mut failed = 0;
+    for message in state.take_sent_messages(chat_id, count) {
+        // Telegram refuses anyway, no need to ask
+        if chrono::Utc::now() - message.time > MAX_DELETE_AGE {
+            failed += 1;
+            continue;
+        }
+        match bot.delete_message(chat_id, message.id).await {
+            Ok(_) => deleted += 1,
+            // Someone else already deleted it, which is just as good
+            Err(RequestError::Api(ApiError::MessageToDeleteNotFound)) => deleted += 1,
+            Err(e) => {
+                warn!(
+                    "Couldn't delete message {} in {}: {}",
+                    message.id, chat_id, e
+                );
+                failed += 1;
+            }
+        }
+    }
+    (deleted, failed)
+}
+
+/// One prompt of the `/history` browser, `position` counts back from the newest.
+fn format_history_page(prompts: &[PromptEntry], position: usize) -> String {
+    let entry = &prompts[position];
+    let prompt: String = entry.prompt.chars().take(HISTORY_PAGE_LIMIT).collect();
+    format!(
+        "Prompt {} of {}, sent {}:\n\n{}",
+        position + 1,
+        prompts.len(),
+        entry.time.format("%Y-%m-%d %H:%M"),
+        prompt
+    )
+}
+
+/// The buttons under a `/history` page. The user id is part of the data, so nobody else in a
+/// group can flip through someone's history.
+fn history_keyboard(
+    user_id: UserId,
+    prompts: &[PromptEntry],
+    position: usize,
+) -> InlineKeyboardMarkup {
+    let mut navigation = Vec::new();
+    if position + 1 < prompts.len() {
+        navigation.push(InlineKeyboardButton::callback(
+            "« Older",
+            format!("history:{}:{}", user_id, position + 1),
+        ));
+    }
+    if position > 0 {
+        navigation.push(InlineKeyboardButton::callback(
+            "Newer »",
+            format!("history:{}:{}", user_id, position - 1),
+        ));
+    }
+    // New prompts shift the positions, so the one to run is found by its time instead
+    let rerun = InlineKeyboardButton::callback(
+        "Run again",
+        format!(
+            "rerun:{}:{}",
+            user_id,
+            prompts[position].time.timestamp_millis()
+        ),
+    );
+    InlineKeyboardMarkup::new([navigation, vec![rerun]])
+}
+
+/// Lists the `/history` matches, as many as fit in a single message.
+fn format_prompt_history(entries: &[PromptEntry]) -> String {
+    if entries.is_empty() {
+        return "None of your prompts match that.".to_string();
+    }
+
+    let mut message = String::new();
+    let mut shown = 0;
+    for entry in entries {
+        let prompt: String = entry.prompt.chars().take(HISTORY_PROMPT_LIMIT).collect();
+        let line = format!("{}: {}\n", entry.time.format("%Y-%m-%d %H:%M"), prompt);
+        // Leave room for the note about the matches that didn't fit
+        if message.chars().count() + line.chars().count() > MESSAGE_LIMIT - 100 {
+            break;
+        }
+        message.push_str(&line);
+        shown += 1;
+    }
+    if shown < entries.len() {
+        message.push_str(&format!(
+            "... and {} older matches, try a more specific search.",
+            entries.len() - shown
+        ));
+    }
+    message
+}
+
+/// Lists the parameters in a request body, leaving out the conversation itself.
+fn format_params(path: &str, body: &Value) -> String {
+    let params = body
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| !matches!(name.as_str(), "messages" | "prompt"))
+        .map(|(name, value)| match value {
+            Value::String(value) => format!("{}: {}", name, value),
+            value => format!("{}: {}", name, value),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "Parameters sent to {} from this chat:\n{}\n\nAnything not listed, like top_p or the seed, uses the server's defaults.",
+        path, params
+    )
+}
+
+/// Sends the configured welcome sticker and photo. A wrong file id only costs the media,
+/// the greeting text is sent either way.
+async fn send_welcome_media(bot: &Bot, msg: &Message, config: &Config) {
+    if let Some(sticker) = &config.welcome_sticker {
+        let mut request = bot.send_sticker(msg.chat.id, InputFile::file_id(sticker));
+        if let Some(thread_id) = topic_thread_id(msg) {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Err(e) = request.await {
+            warn!("Couldn't send the welcome sticker: {}", e);
+        }
+    }
+    if let Some(photo) = &config.welcome_photo {
+        let photo = match reqwest::Url::parse(photo) {
+            Ok(url) => InputFile::url(url),
+            Err(_) => InputFile::file_id(photo),
+        };
+        let mut request = bot.send_photo(msg.chat.id, photo);
+        if let Some(thread_id) = topic_thread_id(msg) {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Err(e) = request.await {
+            warn!("Couldn't send the welcome photo: {}", e);
+        }
+    }
+}
+
+/// Replies with MarkdownV2 `text`, falling back to sending it as plain text if Telegram can't
+/// parse it, so a broken entity doesn't cost the user the whole message.
+async fn reply_markdown(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    text: String,
+) -> ResponseResult<Message> {
+    match reply(bot, msg, config, text.clone())
+        .parse_mode(ParseMode::MarkdownV2)
+        .await
+    {
+        Err(RequestError::Api(e)) if is_parse_error(&e) => {
+            warn!(
+                "Telegram couldn't parse the message, sending it as plain text: {}",
+                e
+            );
+            reply(bot, msg, config, text).await
+        }
+        result => result,
+    }
+}
+
+/// What to tell the user when `/pin` or `/unpin` failed, or the error itself when it isn't about
+/// the bot's rights in the chat.
+fn pin_error_message(e: RequestError) -> Result<String, RequestError> {
+    match &e {
+        RequestError::Api(
+            ApiError::NotEnoughRightsToPinMessage | ApiError::NotEnoughRightsToManagePins,
+        ) => {}
+        RequestError::Api(ApiError::Unknown(message))
+            if message.contains("not enough rights") || message.contains("CHAT_ADMIN_REQUIRED") => {
+        }
+        _ => return Err(e),
+    }
+    warn!("Not allowed to pin messages: {}", e);
+    Ok("I'm not allowed to pin messages here, make me an admin with the right to pin messages first.".to_string())
+}
+
+/// Whether Telegram rejected a message because of its formatting. The error usually comes with
+/// details about the broken entity appended, which teloxide doesn't recognize as `CantParseEntities`.
+fn is_parse_error(e: &ApiError) -> bool {
+    match e {
+        ApiError::CantParseEntities => true,
+        ApiError::Unknown(message) => message.contains("can't parse entities"),
+        _ => false,
+    }
+}
+
+/// Starts a reply to `msg`. In forum groups it's sent into the topic `msg` came from,
+/// otherwise Telegram would put it in the General topic. If `msg` got deleted while the Pi was
+/// still thinking, the reply is sent as a normal message instead of failing.
+fn reply(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    text: impl Into<String>,
+) -> JsonRequest<SendMessage> {
+    let request = bot
+        .send_message(msg.chat.id, text)
+        .reply_to_message_id(msg.id)
+        .allow_sending_without_reply(true)
+        .disable_web_page_preview(!config.link_previews);
+    match topic_thread_id(msg) {
+        Some(thread_id) => request.message_thread_id(thread_id),
+        None => request,
+    }
+}
+
+/// The forum topic `msg` was sent in, replies have to be sent there too.
+fn topic_thread_id(msg: &Message) -> Option<i32> {
+    msg.thread_id.filter(|_| is_forum(&msg.chat))
+}
+
+/// Whether `chat` is a supergroup with topics enabled. Other chats can have a thread id too
+/// (for reply threads), but Telegram only accepts it for forums.
+fn is_forum(chat: &Chat) -> bool {
+    matches!(
+        &chat.kind,
+        ChatKind::Public(ChatPublic {
+            kind: PublicChatKind::Supergroup(PublicChatSupergroup { is_forum: true, .. }),
+            ..
+        })
+    )
+}
+
+/// The number of threads the server reports, only some llama.cpp builds include it in `/props`.
+fn props_threads(props: &Value) -> Option<u64> {
+    props["n_threads"]
+        .as_u64()
+        .or_else(|| props["default_generation_settings"]["n_threads"].as_u64())
+}
+
+/// Tries to change the server's thread count through `POST /props`. Returns whether the server
+/// reports the new count afterwards, most builds don't allow changing it without a restart.
+async fn set_server_threads(threads: u64) -> bool {
+    let response = client()
+        .post(format!("{}/props", URL))
+        .json(&serde_json::json!({ "n_threads": threads }))
+        .send()
+        .await;
+    match response {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => {
+            info!(
+                "Server refused to change the threads: {}",
+                response.status()
+            );
+            return false;
+        }
+        Err(e) => {
+            warn!("Error changing the server threads: {}", e);
+            return false;
+        }
+    }
+    // Some servers accept the request but ignore fields they don't know
+    fetch_props().await.and_then(|props| props_threads(&props)) == Some(threads)
+}
+
+/// Splits `text` into parts that fit in a Telegram message, preferring to split at line breaks.
+fn split_message(text: &str) -> Vec<String> {
+    split_text(text, MESSAGE_LIMIT)
+}
+
+/// Splits `text` into parts of at most `limit` characters, preferring to split at newlines and spaces.
+fn split_text(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while let Some((limit, _)) = rest.char_indices().nth(limit) {
+        let window = &rest[..limit];
+        let split = window
+            .rfind('\n')
+            .or_else(|| window.rfind(' '))
+            .filter(|&split| split > 0)
+            .unwrap_or(limit);
+        chunks.push(rest[..split].to_string());
+        rest = rest[split..].trim_start();
+    }
+    chunks.push(rest.to_string());
+    chunks
+}
+
+/// Replies to `msg` with `text`, replacing the placeholder if there is one.
+/// Long texts are sent in parts with a small delay in between, so we don't hit Telegram's flood
+/// limits. Stops early (before the next part) if `cancel` gets cancelled, e.g. by `/stop`.
+#[instrument(skip_all)]
+async fn send_long_message(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    mut placeholder: Option<Placeholder>,
+    text: &str,
+    cancel: &CancellationToken,
+) -> ResponseResult<()> {
+    let (text, code_files) = extract_code_files(text);
+    for (i, chunk) in split_message(&text).into_iter().enumerate() {
+        if i > 0 {
+            tokio::select! {
+                _ = tokio::time::sleep(config.chunk_delay) => {}
+                _ = cancel.cancelled() => {
+                    info!("Stopped sending the message after {} parts", i);
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(placeholder) = placeholder.take() {
+            match placeholder.finish(bot, chunk.clone()).await {
+                Ok(sent) => {
+                    state.track_sent_message(&sent);
+                    continue;
+                }
+                // Most likely the placeholder was deleted, which is what cancelled the request
+                Err(e) if cancel.is_cancelled() => {
+                    debug!(
+                        "Couldn't update the placeholder of a cancelled request: {}",
+                        e
+                    );
+                    return Ok(());
+                }
+                Err(e) => warn!(
+                    "Couldn't replace the placeholder, sending a new message: {}",
+                    e
+                ),
+            }
+        }
+        let sent = reply(bot, msg, config, chunk).await?;
+        state.track_sent_message(&sent);
+    }
+
+    for (file_name, code) in code_files {
+        if cancel.is_cancelled() {
+            return Ok(());
+        }
+        let mut request = bot
+            .send_document(
+                msg.chat.id,
+                InputFile::memory(code.into_bytes()).file_name(file_name),
+            )
+            .reply_to_message_id(msg.id)
+            .allow_sending_without_reply(true);
+        if let Some(thread_id) = topic_thread_id(msg) {
+            request = request.message_thread_id(thread_id);
+        }
+        let sent = request.await?;
+        state.track_sent_message(&sent);
+    }
+
+    Ok(())
+}
+
+/// Takes the long fenced code blocks out of `text`, so they can be sent as files instead of
+/// getting cut in half by the message limit. Returns the text with a note in place of each block,
+/// and the file name and contents of each one.
+fn extract_code_files(text: &str) -> (String, Vec<(String, String)>) {
+    let mut output = String::new();
+    let mut files = Vec::new();
+    let mut lines = text.split_inclusive('\n');
+    while let Some(line) = lines.next() {
+        // Only blocks with a language tag, so we can pick a file extension
+        let language = line.trim().strip_prefix("```").map(str::trim);
+        let Some(language) = language.filter(|language| !language.is_empty()) else {
+            output.push_str(line);
+            continue;
+        };
+
+        let mut code = String::new();
+        let mut closing = None;
+        for line in lines.by_ref() {
+            if line.trim() == "```" {
+                closing = Some(line);
+                break;
+            }
+            code.push_str(line);
+        }
+
+        if closing.is_some() && code.chars().count() >= CODE_FILE_MIN_CHARS {
+            let file_name = format!("code{}.{}", files.len() + 1, file_extension(language));
+            output.push_str(&format!("(The code is attached as {}.)\n", file_name));
+            files.push((file_name, code));
+        } else {
+            output.push_str(line);
+            output.push_str(&code);
+            output.push_str(closing.unwrap_or_default());
+        }
+    }
+    (output, files)
+}
+
+/// The file extension for a code block's language tag, `txt` for anything unknown.
+fn file_extension(language: &str) -> &str {
+    match language.to_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "java" => "java",
+        "go" => "go",
+        "bash" | "sh" | "shell" => "sh",
+        "html" => "html",
+        "css" => "css",
+        "json" => "json",
+        "yaml" | "yml" => "yml",
+        "toml" => "toml",
+        "sql" => "sql",
+        _ => "txt",
+    }
+}
 
+/// Answers a `/qwen`-style prompt, with the chat's history and a placeholder while it's generated.
+/// `show_reasoning` sends the model's reasoning before the answer, for `/think`.
+async fn answer_prompt(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    state: &State,
+    prompt: String,
+    stop: &[&str],
+    show_reasoning: bool,
+) -> ResponseResult<()> {
+    let request_id = state.next_request_id();
+    Span::current().record("request_id", request_id);
     info!(
-        "{} has started!",
-        bot.get_me().send().await.unwrap().user.username.unwrap()
+        "Received LLM request #{}: {}",
+        request_id,
+        config.loggable(&prompt)
     );
+    if let Some(user) = msg.from() {
+        state.record_prompt(user.id, &prompt);
+    }
+    let quote = prompt_quote(config, msg, &prompt);
+    let prompt = with_quoted_context(msg, prompt);
 
-    Command::repl(bot, answer).await;
+    let cancel = state.cancellation_token(msg.chat.id);
+    let placeholder = Placeholder::start(
+        bot,
+        msg,
+        config,
+        state.average_generation_time(),
+        cancel.clone(),
+    )
+    .await;
+    let response = ask_qwen(
+        bot,
+        msg,
+        config,
+        state,
+        request_id,
+        prompt,
+        stop,
+        placeholder.as_ref().and_then(Placeholder::retry_notice),
+        &cancel,
+    )
+    .await
+    .unwrap_or_else(|e| e);
+    let (reasoning, response) = answer_text(&response, state.chat_settings(msg.chat.id).max_length);
+    let response = match quote {
+        Some(quote) => quote + &response,
+        None => response,
+    };
+    let placeholder = match reasoning {
+        Some(reasoning) if show_reasoning && !reasoning.is_empty() => {
+            let reasoning = format_reasoning(&reasoning);
+            send_long_message(bot, msg, config, state, placeholder, &reasoning, &cancel).await?;
+            None
+        }
+        _ => placeholder,
+    };
+    send_long_message(bot, msg, config, state, placeholder, &response, &cancel).await
 }
 
-#[derive(BotCommands, Clone)]
-#[command(
-    rename_rule = "lowercase",
-    description = "This bot is 100% hosted on a 512MB Raspberry Pi Zero 2 W. Expect low performance and low quality.\n\nThese commands are supported:"
-)]
-enum Command {
-    #[command(description = "LLM request")]
-    Qwen(String),
-    #[command(description = "Prints this help")]
-    Help,
-    #[command(description = "Health check")]
-    Health,
-}
+#[instrument(skip_all, fields(chat = %msg.chat.id, request_id))]
+async fn answer(
+    bot: Bot,
+    msg: Message,
+    mut cmd: Command,
+    shared_config: Arc<RwLock<Config>>,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    // A snapshot, so a /reload can't change the config halfway through a request
+    let config = shared_config.read().unwrap().clone();
+    if cmd.is_admin_only() && !config.is_admin(&msg) {
+        let sent = reply(
+            &bot,
+            &msg,
+            &config,
+            "This command is only available to admins.",
+        )
+        .await?;
+        state.track_sent_message(&sent);
+        return Ok(());
+    }
 
-async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
-    match cmd {
-        Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                .reply_to_message_id(msg.id)
-                .await?
+    if let (Some(notice), Some(user)) = (&config.welcome_notice, msg.from()) {
+        if state.mark_welcomed(user.id) {
+            info!("Sending the welcome notice to {}", user.id);
+            if let Err(e) = state.save_welcomed(&config.welcomed_file) {
+                warn!("Couldn't save the welcomed users: {}", e);
+            }
+            let notice = match config.daily_request_limit {
+                Some(limit) => format!(
+                    "{}\n\nThe bot answers at most {} requests a day, shared by everyone.",
+                    notice, limit
+                ),
+                None => notice.clone(),
+            };
+            let sent = reply(&bot, &msg, &config, notice).await?;
+            state.track_sent_message(&sent);
         }
-        Command::Qwen(prompt) => {
-            info!("Received LLM request: {}", prompt);
-            let url = format!("{}/v1/chat/completions", URL);
-
-            // Create headers
-            let mut headers = HeaderMap::new();
-            headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
-            headers.insert(AUTHORIZATION, "Bearer amogus".parse().unwrap());
-
-            // Create the body
-            let body = json!({
-                "model": "amogus", // model doesn't matter, llama.cpp uses qwen 0.5b under the hood
-                "messages": [
-                    {
-                        "role": "user",
-                        "content": prompt
-                    }
-                ],
-                "temperature": 0.4, // low temperature because this model is so small any variation will probably be bad
-                "max_tokens": 256, // sometimes the model generates infinite tokens
-                "frequency_penalty": 1.1, // sometimes the model repeats itself
-            });
+    }
+
+    if cmd.uses_model() && state.is_paused() {
+        let sent = reply(
+            &bot,
+            &msg,
+            &config,
+            "The bot is paused for maintenance, try again later.",
+        )
+        .await?;
+        state.track_sent_message(&sent);
+        return Ok(());
+    }
+
+    if cmd.uses_model() && !config.is_admin(&msg) {
+        if let Some(until) = config.maintenance_until() {
+            let sent = reply(
+                &bot,
+                &msg,
+                &config,
+                format!(
+                    "The bot is under scheduled maintenance until {} (UTC{}), try again later.",
+                    until.format("%H:%M"),
+                    config.maintenance_offset
+                ),
+            )
+            .await?;
+            state.track_sent_message(&sent);
+            return Ok(());
+        }
+    }
+
+    if config.injection_guard != InjectionGuard::Off {
+        if let Some(prompt) = cmd.chat_prompt_mut() {
+            if let Some(reason) = guard::suspected_injection(prompt) {
+                warn!(
+                    "Suspected prompt injection in {} ({}): {}",
+                    msg.chat.id,
+                    reason,
+                    config.loggable(prompt)
+                );
+                if config.injection_guard == InjectionGuard::Reject {
+                    let sent = reply(
+                        &bot,
+                        &msg,
+                        &config,
+                        "That prompt looks like it's trying to change the bot's instructions, so it wasn't sent.",
+                    )
+                    .await?;
+                    state.track_sent_message(&sent);
+                    return Ok(());
+                }
+                *prompt = guard::sanitize(prompt);
+            }
+        }
+    }
+
+    // Only a quick refusal, every model request the command makes is counted when it's sent
+    if let Some(limit) = config.daily_request_limit {
+        if cmd.uses_model()
+            && !config.is_admin(&msg)
+            && state.daily_limit_reached(config.today(), limit)
+        {
+            info!("Daily limit of {} requests reached", limit);
+            let sent = reply(&bot, &msg, &config, DAILY_LIMIT_MESSAGE).await?;
+            state.track_sent_message(&sent);
+            return Ok(());
+        }
+    }
+
+    let show_reasoning = matches!(cmd, Command::Think(_));
+    // Generation stops at the first line break, so the answer is a single line
+    let stop: &[&str] = if matches!(cmd, Command::Line(_)) {
+        &["\n"]
+    } else {
+        &[]
+    };
+    let sent = match cmd {
+        Command::Help => reply(&bot, &msg, &config, Command::descriptions().to_string()).await?,
+        Command::Start => {
+            send_welcome_media(&bot, &msg, &config).await;
+            reply(
+                &bot,
+                &msg,
+                &config,
+                format!("{}\n\n{}", config.greeting, Command::descriptions()),
+            )
+            .await?
+        }
+        Command::Random => {
+            let prompts = &config.random_prompts;
+            match prompts.get((llm::jitter() * prompts.len() as f64) as usize) {
+                Some(prompt) => {
+                    info!("Picked the random prompt {:?}", prompt);
+                    return answer_prompt(&bot, &msg, &config, &state, prompt.clone(), &[], false)
+                        .await;
+                }
+                None => reply(&bot, &msg, &config, "No random prompts are configured.").await?,
+            }
+        }
+        Command::Qwen(prompt) | Command::Line(prompt) | Command::Think(prompt) => {
+            return answer_prompt(&bot, &msg, &config, &state, prompt, stop, show_reasoning).await;
+        }
+        Command::Say(prompt) => {
+            if config.tts_url.is_none() {
+                reply(
+                    &bot,
+                    &msg,
+                    &config,
+                    "Voice answers aren't set up on this bot.",
+                )
+                .await?
+            } else {
+                let request_id = state.next_request_id();
+                Span::current().record("request_id", request_id);
+                info!(
+                    "Received voice LLM request #{}: {}",
+                    request_id,
+                    config.loggable(&prompt)
+                );
+                if let Some(user) = msg.from() {
+                    state.record_prompt(user.id, &prompt);
+                }
+                let prompt = with_quoted_context(&msg, prompt);
 
-            // Send the request
-            let client = reqwest::Client::new();
-
-            // Before we send the request, send the typing indicator every 5 seconds in a different thread
-            let flag = Arc::new(AtomicBool::new(false)); // this is for stopping the typing indicator. we do it this way because it's in a different thread and we need thread safety.
-            let flag_clone = Arc::clone(&flag);
-
-            let bot_clone = bot.clone();
-            let msg_clone = msg.clone();
-            tokio::spawn(async move {
-                loop {
-                    if flag_clone.load(Ordering::Relaxed) {
-                        info!("Stopping typing indicator");
-                        break;
-                    }
-                    debug!("Sending typing indicator...");
-                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                    bot_clone
-                        .send_chat_action(msg_clone.chat.id, teloxide::types::ChatAction::Typing)
+                let cancel = state.cancellation_token(msg.chat.id);
+                let placeholder = Placeholder::start(
+                    &bot,
+                    &msg,
+                    &config,
+                    state.average_generation_time(),
+                    cancel.clone(),
+                )
+                .await;
+                let response = match ask_qwen(
+                    &bot,
+                    &msg,
+                    &config,
+                    &state,
+                    request_id,
+                    prompt,
+                    &[],
+                    placeholder.as_ref().and_then(Placeholder::retry_notice),
+                    &cancel,
+                )
+                .await
+                {
+                    Ok(response) => {
+                        answer_text(&response, state.chat_settings(msg.chat.id).max_length).1
+                    }
+                    Err(e) => {
+                        return send_long_message(
+                            &bot,
+                            &msg,
+                            &config,
+                            &state,
+                            placeholder,
+                            &e,
+                            &cancel,
+                        )
                         .await
-                        .unwrap();
+                    }
+                };
+
+                match tts::synthesize(&config, &response).await {
+                    Some(Ok(voice)) if !cancel.is_cancelled() => {
+                        if let Some(placeholder) = placeholder {
+                            placeholder.remove(&bot).await;
+                        }
+                        let mut request = bot
+                            .send_voice(msg.chat.id, InputFile::memory(voice))
+                            .reply_to_message_id(msg.id)
+                            .allow_sending_without_reply(true);
+                        if let Some(thread_id) = topic_thread_id(&msg) {
+                            request = request.message_thread_id(thread_id);
+                        }
+                        request.await?
+                    }
+                    // The answer is there anyway, so at least send it as text
+                    result => {
+                        if let Some(Err(e)) = result {
+                            warn!("Couldn't synthesize the answer, sending it as text: {}", e);
+                        }
+                        return send_long_message(
+                            &bot,
+                            &msg,
+                            &config,
+                            &state,
+                            placeholder,
+                            &response,
+                            &cancel,
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+        Command::Batch(prompts) => {
+            let prompts: Vec<&str> = prompts
+                .lines()
+                .map(str::trim)
+                .filter(|prompt| !prompt.is_empty())
+                .collect();
+            if prompts.is_empty() || prompts.len() > MAX_BATCH_PROMPTS {
+                let message = format!(
+                    "Put up to {} questions after /batch, one per line.",
+                    MAX_BATCH_PROMPTS
+                );
+                let sent = reply(&bot, &msg, &config, message).await?;
+                state.track_sent_message(&sent);
+                return Ok(());
+            }
+
+            // One at a time, so a batch only ever takes one of the model's slots
+            let cancel = state.cancellation_token(msg.chat.id);
+            for prompt in prompts {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let request_id = state.next_request_id();
+                Span::current().record("request_id", request_id);
+                info!(
+                    "Received batch LLM request #{}: {}",
+                    request_id,
+                    config.loggable(prompt)
+                );
+                if let Some(user) = msg.from() {
+                    state.record_prompt(user.id, prompt);
                 }
-            });
 
-            info!("Sending request to {}", url);
-            let now = std::time::Instant::now();
-            let res = client.post(&url).headers(headers).json(&body).send().await;
-            info!("Request took {}ms", now.elapsed().as_millis());
-            // Stop the typing indicator
-            flag.store(true, Ordering::Relaxed);
-            // There is probably a better way to do this but this works for now
+                let placeholder = Placeholder::start(
+                    &bot,
+                    &msg,
+                    &config,
+                    state.average_generation_time(),
+                    cancel.clone(),
+                )
+                .await;
+                let response = ask_qwen(
+                    &bot,
+                    &msg,
+                    &config,
+                    &state,
+                    request_id,
+                    prompt.to_string(),
+                    &[],
+                    placeholder.as_ref().and_then(Placeholder::retry_notice),
+                    &cancel,
+                )
+                .await
+                .unwrap_or_else(|e| e);
+                let (_, response) =
+                    answer_text(&response, state.chat_settings(msg.chat.id).max_length);
+                // Every answer says which question it belongs to, they all reply to the same message
+                let response = quote_prompt(prompt) + &response;
+                send_long_message(&bot, &msg, &config, &state, placeholder, &response, &cancel)
+                    .await?;
+            }
+            return Ok(());
+        }
+        Command::Complete(prompt) => {
+            let request_id = state.next_request_id();
+            Span::current().record("request_id", request_id);
+            info!(
+                "Received text completion request #{}: {}",
+                request_id,
+                config.loggable(&prompt)
+            );
+            if let Some(user) = msg.from() {
+                state.record_prompt(user.id, &prompt);
+            }
 
-            let res = match res {
-                Ok(res) => res,
+            let cancel = state.cancellation_token(msg.chat.id);
+            let placeholder = Placeholder::start(
+                &bot,
+                &msg,
+                &config,
+                state.average_generation_time(),
+                cancel.clone(),
+            )
+            .await;
+            let response = complete_text(&bot, &msg, &config, &state, request_id, &prompt, &cancel)
+                .await
+                .unwrap_or_else(|e| e);
+            let response = truncate_response(response, state.chat_settings(msg.chat.id).max_length);
+            let response = match prompt_quote(&config, &msg, &prompt) {
+                Some(quote) => quote + &response,
+                None => response,
+            };
+            return send_long_message(&bot, &msg, &config, &state, placeholder, &response, &cancel)
+                .await;
+        }
+        Command::LongSummarize(args) => {
+            let request_id = state.next_request_id();
+            Span::current().record("request_id", request_id);
+            let text = match summary_input(&bot, &msg, &config, &args).await {
+                Ok(text) => text,
                 Err(e) => {
-                    error!("Error sending request: {}", e);
-                    bot.send_message(msg.chat.id, "An error occurred while sending the request.")
-                        .reply_to_message_id(msg.id)
-                        .await?;
+                    let sent = reply(&bot, &msg, &config, e).await?;
+                    state.track_sent_message(&sent);
                     return Ok(());
                 }
             };
+            info!(
+                "Received long summary request #{} for {} characters",
+                request_id,
+                text.chars().count()
+            );
+
+            let cancel = state.cancellation_token(msg.chat.id);
+            let placeholder = Placeholder::start(&bot, &msg, &config, None, cancel.clone()).await;
+            let summary =
+                summarize_long_text(&bot, &msg, &config, &state, request_id, &text, &cancel)
+                    .await
+                    .unwrap_or_else(|e| e);
+            return send_long_message(&bot, &msg, &config, &state, placeholder, &summary, &cancel)
+                .await;
+        }
+        Command::Bias(args) => {
+            let args = args.trim();
+            let message = if args.is_empty() {
+                let settings = state.chat_settings(msg.chat.id);
+                if settings.logit_bias.is_empty() {
+                    "No token biases are set.".to_string()
+                } else {
+                    format!(
+                        "Current token biases: {}",
+                        format_logit_bias(&settings.logit_bias)
+                    )
+                }
+            } else if args == "clear" {
+                state.update_chat_settings(msg.chat.id, |settings| settings.logit_bias.clear());
+                "Token biases cleared.".to_string()
+            } else {
+                match parse_logit_bias(args) {
+                    Ok(logit_bias) => {
+                        info!("Setting token biases for {}: {:?}", msg.chat.id, logit_bias);
+                        let count = logit_bias.len();
+                        state.update_chat_settings(msg.chat.id, |settings| {
+                            settings.logit_bias = logit_bias
+                        });
+                        format!("Set {} token biases.", count)
+                    }
+                    Err(e) => e,
+                }
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Typing(args) => {
+            let message = match args.trim() {
+                "" => {
+                    if state.chat_settings(msg.chat.id).hide_typing {
+                        "The typing indicator is off in this chat."
+                    } else {
+                        "The typing indicator is on in this chat."
+                    }
+                }
+                "off" => {
+                    info!("Turning the typing indicator off for {}", msg.chat.id);
+                    state.update_chat_settings(msg.chat.id, |settings| settings.hide_typing = true);
+                    "The bot won't show \"typing...\" anymore while it's thinking."
+                }
+                "on" => {
+                    info!("Turning the typing indicator on for {}", msg.chat.id);
+                    state
+                        .update_chat_settings(msg.chat.id, |settings| settings.hide_typing = false);
+                    "The bot will show \"typing...\" again while it's thinking."
+                }
+                _ => "Invalid value, expected on or off.",
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Summary(args) => {
+            let message = match args.trim() {
+                "" => {
+                    if state.chat_settings(msg.chat.id).summarize_history {
+                        "Older messages are summarized in this chat."
+                    } else {
+                        "Older messages are forgotten in this chat."
+                    }
+                }
+                "on" => {
+                    info!("Turning the history summary on for {}", msg.chat.id);
+                    state.update_chat_settings(msg.chat.id, |settings| {
+                        settings.summarize_history = true
+                    });
+                    "Messages that no longer fit in the memory will be summarized instead of forgotten."
+                }
+                "off" => {
+                    info!("Turning the history summary off for {}", msg.chat.id);
+                    state.update_chat_settings(msg.chat.id, |settings| {
+                        settings.summarize_history = false
+                    });
+                    state.clear_history_summary(msg.chat.id);
+                    "Older messages will be forgotten again, the summary is deleted."
+                }
+                _ => "Invalid value, expected on or off.",
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Memory(args) => {
+            let message = match args.trim() {
+                "" => {
+                    let settings = state.chat_settings(msg.chat.id);
+                    if config.history_enabled(&settings) {
+                        format!(
+                            "The bot remembers the last {} messages in this chat.",
+                            config.history_turns(&settings)
+                        )
+                    } else {
+                        "The bot doesn't remember previous messages in this chat.".to_string()
+                    }
+                }
+                "off" => {
+                    info!("Turning the history off for {}", msg.chat.id);
+                    state.update_chat_settings(msg.chat.id, |settings| {
+                        settings.history_enabled = Some(false)
+                    });
+                    "The bot won't remember previous messages anymore, every prompt is answered on its own.".to_string()
+                }
+                "on" => {
+                    info!("Turning the history on for {}", msg.chat.id);
+                    state.update_chat_settings(msg.chat.id, |settings| {
+                        settings.history_enabled = Some(true)
+                    });
+                    let turns = config.history_turns(&state.chat_settings(msg.chat.id));
+                    if turns == 0 {
+                        "The memory is on, but it's set to 0 messages. Pick how many with e.g. /memory 4".to_string()
+                    } else {
+                        format!("The bot will now remember the last {} messages.", turns)
+                    }
+                }
+                args => match args.parse::<usize>() {
+                    Ok(turns) => {
+                        let turns = turns.min(config.max_history_turns);
+                        info!("Setting history turns for {} to {}", msg.chat.id, turns);
+                        // Picking a number means the chat wants the history, even if it was off
+                        state.update_chat_settings(msg.chat.id, |settings| {
+                            settings.history_turns = Some(turns);
+                            settings.history_enabled = Some(true);
+                        });
+                        format!("The bot will now remember the last {} messages.", turns)
+                    }
+                    Err(_) => format!(
+                        "Invalid number of messages, expected a number between 0 and {}, on or off.",
+                        config.max_history_turns
+                    ),
+                },
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Mode(args) => {
+            let message = match args.trim() {
+                "" => {
+                    let settings = state.chat_settings(msg.chat.id);
+                    format!(
+                        "This chat uses the {} prompt format.",
+                        format_prompt_format(settings.prompt_format)
+                    )
+                }
+                mode @ ("chat" | "instruct") => {
+                    let prompt_format = if mode == "chat" {
+                        PromptFormat::Chat
+                    } else {
+                        PromptFormat::Instruct
+                    };
+                    info!("Setting the prompt format for {} to {}", msg.chat.id, mode);
+                    state.update_chat_settings(msg.chat.id, |settings| {
+                        settings.prompt_format = prompt_format
+                    });
+                    format!("This chat now uses the {} prompt format.", mode)
+                }
+                _ => "Invalid prompt format, expected chat or instruct.".to_string(),
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Format(args) => {
+            let message = match args.trim() {
+                "" => {
+                    let settings = state.chat_settings(msg.chat.id);
+                    format!(
+                        "Answer format of this chat: {}",
+                        format_answer_format(settings.answer_format)
+                    )
+                }
+                format @ ("bullets" | "prose" | "off") => {
+                    let answer_format = match format {
+                        "bullets" => Some(AnswerFormat::Bullets),
+                        "prose" => Some(AnswerFormat::Prose),
+                        _ => None,
+                    };
+                    info!(
+                        "Setting the answer format for {} to {}",
+                        msg.chat.id, format
+                    );
+                    state.update_chat_settings(msg.chat.id, |settings| {
+                        settings.answer_format = answer_format
+                    });
+                    format!(
+                        "Answer format of this chat: {}",
+                        format_answer_format(answer_format)
+                    )
+                }
+                _ => "Invalid answer format, expected bullets, prose or off.".to_string(),
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Model(args) => {
+            let args = args.trim();
+            let message = if args.is_empty() {
+                let settings = state.chat_settings(msg.chat.id);
+                format!("This chat uses {}.", format_model(&config, &settings))
+            } else if args == "default" {
+                state.update_chat_settings(msg.chat.id, |settings| settings.model = None);
+                format!("This chat uses the default model ({}) again.", config.model)
+            } else {
+                info!(
+                    "Setting the model for {} to {} ({})",
+                    msg.chat.id,
+                    args,
+                    config.resolve_model(args)
+                );
+                state.update_chat_settings(msg.chat.id, |settings| {
+                    settings.model = Some(args.to_string())
+                });
+                let settings = state.chat_settings(msg.chat.id);
+                format!("This chat now uses {}.", format_model(&config, &settings))
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Models => {
+            let message = if config.model_aliases.is_empty() {
+                format!(
+                    "No model aliases are configured, the default model is {}.",
+                    config.model
+                )
+            } else {
+                let aliases = config
+                    .model_aliases
+                    .iter()
+                    .map(|(alias, id)| format!("{}: {}", alias, id))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                format!(
+                    "Model aliases:\n{}\n\nDefault model: {}",
+                    aliases, config.model
+                )
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Persona(args) => {
+            let args = args.trim();
+            let message = if args.is_empty() {
+                match state.chat_settings(msg.chat.id).persona {
+                    Some(persona) => format!("This chat uses the {} persona.", persona),
+                    None => "This chat doesn't use a persona.".to_string(),
+                }
+            } else if args == "off" {
+                state.update_chat_settings(msg.chat.id, |settings| settings.persona = None);
+                "Persona turned off.".to_string()
+            } else if config.personas.iter().any(|(name, _)| name == args) {
+                info!("Setting the persona for {} to {}", msg.chat.id, args);
+                state.update_chat_settings(msg.chat.id, |settings| {
+                    settings.persona = Some(args.to_string())
+                });
+                format!("This chat now uses the {} persona.", args)
+            } else {
+                format!("There's no {} persona, see /personas", args)
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Personas => {
+            let message = if config.personas.is_empty() {
+                "No personas are configured.".to_string()
+            } else {
+                let names = config
+                    .personas
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Personas: {}\n\nPick one with /persona <name>", names)
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::MaxTokens(args) => {
+            let args = args.trim();
+            let message = if args.is_empty() {
+                let settings = state.chat_settings(msg.chat.id);
+                format!(
+                    "Answers in this chat are limited to {} tokens.",
+                    config.max_tokens(&settings)
+                )
+            } else if args == "default" {
+                state.update_chat_settings(msg.chat.id, |settings| settings.max_tokens = None);
+                format!(
+                    "Answers are limited to the default of {} tokens again.",
+                    config.max_tokens
+                )
+            } else {
+                match args.parse::<u32>() {
+                    Ok(max_tokens) if max_tokens > 0 => {
+                        let max_tokens = max_tokens.min(config.max_tokens_limit);
+                        info!("Setting max tokens for {} to {}", msg.chat.id, max_tokens);
+                        state.update_chat_settings(msg.chat.id, |settings| {
+                            settings.max_tokens = Some(max_tokens)
+                        });
+                        format!("Answers will now be limited to {} tokens.", max_tokens)
+                    }
+                    _ => format!(
+                        "Invalid number of tokens, expected a number between 1 and {}.",
+                        config.max_tokens_limit
+                    ),
+                }
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::MaxLen(args) => {
+            let args = args.trim();
+            let message = if args.is_empty() {
+                match state.chat_settings(msg.chat.id).max_length {
+                    Some(max_length) => format!(
+                        "Answers in this chat are cut off after {} characters.",
+                        max_length
+                    ),
+                    None => "Answers in this chat aren't cut off.".to_string(),
+                }
+            } else if args == "off" {
+                state.update_chat_settings(msg.chat.id, |settings| settings.max_length = None);
+                "Answers won't be cut off anymore.".to_string()
+            } else {
+                match args.parse::<usize>() {
+                    Ok(max_length) if max_length > 0 => {
+                        info!("Setting max length for {} to {}", msg.chat.id, max_length);
+                        state.update_chat_settings(msg.chat.id, |settings| {
+                            settings.max_length = Some(max_length)
+                        });
+                        format!(
+                            "Answers will now be cut off after {} characters.",
+                            max_length
+                        )
+                    }
+                    _ => "Invalid length, expected a number of characters, e.g. /maxlen 500"
+                        .to_string(),
+                }
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Budget(args) => {
+            let args = args.trim();
+            let message = if args.is_empty() {
+                let settings = state.chat_settings(msg.chat.id);
+                format!(
+                    "Time budget of this chat: {}",
+                    format_time_budget(config.time_budget(&settings))
+                )
+            } else if args == "off" {
+                info!("Disabling the time budget for {}", msg.chat.id);
+                state.update_chat_settings(msg.chat.id, |settings| {
+                    settings.time_budget_secs = Some(0)
+                });
+                "Answers in this chat can now take as long as they need.".to_string()
+            } else {
+                match args.parse::<u64>() {
+                    Ok(secs) if secs > 0 => {
+                        info!("Setting the time budget for {} to {}s", msg.chat.id, secs);
+                        state.update_chat_settings(msg.chat.id, |settings| {
+                            settings.time_budget_secs = Some(secs)
+                        });
+                        format!("Answers will now be cut off after {} seconds.", secs)
+                    }
+                    _ => "Invalid time budget, expected a number of seconds or off.".to_string(),
+                }
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::History(query) => {
+            let query = query.trim();
+            match msg.from() {
+                Some(user) if query.is_empty() => {
+                    // An empty search matches every prompt
+                    let prompts = state.search_prompts(user.id, "");
+                    if prompts.is_empty() {
+                        reply(&bot, &msg, &config, "You haven't sent any prompts yet.").await?
+                    } else {
+                        reply(&bot, &msg, &config, format_history_page(&prompts, 0))
+                            .reply_markup(history_keyboard(user.id, &prompts, 0))
+                            .await?
+                    }
+                }
+                Some(user) => {
+                    let message = format_prompt_history(&state.search_prompts(user.id, query));
+                    reply(&bot, &msg, &config, message).await?
+                }
+                None => reply(&bot, &msg, &config, "Only users have a prompt history.").await?,
+            }
+        }
+        Command::Clear(args) => {
+            let args = args.trim();
+            let count = if args.is_empty() {
+                Ok(DEFAULT_CLEAR_COUNT)
+            } else {
+                args.parse::<usize>()
+            };
+            let message = match count {
+                Ok(count) => {
+                    info!("Deleting up to {} messages in {}", count, msg.chat.id);
+                    let (deleted, failed) =
+                        delete_sent_messages(&bot, &state, msg.chat.id, count).await;
+                    if failed == 0 {
+                        format!("Deleted {} messages.", deleted)
+                    } else {
+                        format!(
+                            "Deleted {} messages, {} were too old to delete.",
+                            deleted, failed
+                        )
+                    }
+                }
+                Err(_) => "Invalid number of messages, e.g. /clear 5".to_string(),
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Pin => {
+            let message = match msg.reply_to_message() {
+                None => "Reply to one of my answers with /pin.".to_string(),
+                Some(replied) => {
+                    let me = bot.get_me().await?;
+                    if replied.from().map(|user| user.id) != Some(me.id) {
+                        "I can only pin my own answers.".to_string()
+                    } else {
+                        info!("Pinning message {} in {}", replied.id, msg.chat.id);
+                        match bot.pin_chat_message(msg.chat.id, replied.id).await {
+                            Ok(_) => "Pinned.".to_string(),
+                            Err(e) => pin_error_message(e)?,
+                        }
+                    }
+                }
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Unpin => {
+            let mut request = bot.unpin_chat_message(msg.chat.id);
+            // Without a reply Telegram unpins the latest pinned message
+            if let Some(replied) = msg.reply_to_message() {
+                request = request.message_id(replied.id);
+            }
+            info!("Unpinning a message in {}", msg.chat.id);
+            let message = match request.await {
+                Ok(_) => "Unpinned.".to_string(),
+                Err(e) => pin_error_message(e)?,
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Settings => {
+            let settings = state.chat_settings(msg.chat.id);
+            let logit_bias = if settings.logit_bias.is_empty() {
+                "none".to_string()
+            } else {
+                format_logit_bias(&settings.logit_bias)
+            };
+            let message = format!(
+                "Settings of this chat:\nModel: {}\nPersona: {}\nPrompt format: {}\nAnswer format: {}\nMemory: {}\nMax tokens: {}\nMax length: {}\nToken biases: {}\nTime budget: {}\nTyping indicator: {}\nHistory summary: {}",
+                format_model(&config, &settings),
+                settings.persona.as_deref().unwrap_or("none"),
+                format_prompt_format(settings.prompt_format),
+                format_answer_format(settings.answer_format),
+                format_memory(&config, &settings),
+                config.max_tokens(&settings),
+                settings
+                    .max_length
+                    .map_or("none".to_string(), |max_length| format!("{} characters", max_length)),
+                logit_bias,
+                format_time_budget(config.time_budget(&settings)),
+                if settings.hide_typing { "off" } else { "on" },
+                if settings.summarize_history { "on" } else { "off" }
+            );
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Params => {
+            let settings = state.chat_settings(msg.chat.id);
+            let (path, body) = conversation_request(&config, "", None, &[], &settings);
+
+            reply(&bot, &msg, &config, format_params(path, &body)).await?
+        }
+        Command::ForgetMe => {
+            let message = match msg.from() {
+                Some(user) => {
+                    info!("Forgetting the data of user {}", user.id);
+                    state.forget_user(user.id);
+                    if config.welcome_notice.is_some() {
+                        if let Err(e) = state.save_welcomed(&config.welcomed_file) {
+                            warn!("Couldn't save the welcomed users: {}", e);
+                        }
+                    }
+                    "Done, I forgot your prompt history and the settings and conversation of our private chat. The error log the admins see is kept, it only has what the server said."
+                }
+                None => "I don't know who sent this, so there's nothing to forget.",
+            };
 
-            // Parse the response
-            let res_text = res.text().await;
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Raw(prompt) => {
+            let request_id = state.next_request_id();
+            Span::current().record("request_id", request_id);
+            info!(
+                "Received raw LLM request #{}: {}",
+                request_id,
+                config.loggable(&prompt)
+            );
+
+            let cancel = state.cancellation_token(msg.chat.id);
+            let settings = state.chat_settings(msg.chat.id);
+            let res_text = match wait_for_turn(&bot, &msg, &config, &state, &cancel).await {
+                Some(_permit) => {
+                    request_completion(&bot, &msg, &config, &prompt, &settings, &cancel).await
+                }
+                None => Err(CompletionError::Cancelled),
+            };
             let res_text = match res_text {
                 Ok(res_text) => res_text,
                 Err(e) => {
-                    error!("Error reading response: {}", e);
-                    bot.send_message(msg.chat.id, "An error occurred while reading the response.")
-                        .reply_to_message_id(msg.id)
-                        .await?;
+                    let sent = reply(
+                        &bot,
+                        &msg,
+                        &config,
+                        report_completion_error(&bot, &config, &state, request_id, e),
+                    )
+                    .await?;
+                    state.track_sent_message(&sent);
                     return Ok(());
                 }
             };
-            let parsed_response = serde_json::from_str::<Value>(&res_text);
-            let parsed_response = match parsed_response {
-                Ok(parsed_response) => parsed_response,
-                Err(e) => {
-                    error!("Error parsing response: {}", e);
-                    bot.send_message(msg.chat.id, "An error occurred while parsing the response.")
-                        .reply_to_message_id(msg.id)
-                        .await?;
-                    return Ok(());
+
+            reply_markdown(&bot, &msg, &config, format_raw_response(&res_text)).await?
+        }
+        Command::Errors => {
+            let errors = state.recent_errors();
+            let message = if errors.is_empty() {
+                "No errors recorded since the last restart.".to_string()
+            } else {
+                errors
+                    .iter()
+                    .map(|entry| {
+                        let message: String =
+                            entry.message.chars().take(ERROR_MESSAGE_LIMIT).collect();
+                        let mut line = format!(
+                            "{} #{}: {}",
+                            entry.time.format("%Y-%m-%d %H:%M:%S"),
+                            entry.request_id,
+                            message
+                        );
+                        if let Some(diagnostics) = entry.diagnostics.get() {
+                            let diagnostics: String =
+                                diagnostics.chars().take(ERROR_DIAGNOSTICS_LIMIT).collect();
+                            line.push_str(&format!("\n  Server: {}", diagnostics));
+                        }
+                        line
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+
+            let cancel = state.cancellation_token(msg.chat.id);
+            return send_long_message(&bot, &msg, &config, &state, None, &message, &cancel).await;
+        }
+        Command::CompareParams(args) => {
+            let parts: Vec<&str> = args.splitn(3, '|').map(str::trim).collect();
+            let parsed = match parts[..] {
+                [a, b, prompt] if !a.is_empty() && !b.is_empty() && !prompt.is_empty() => {
+                    parse_param_set(&config, a).and_then(|params_a| {
+                        Ok((
+                            [(a, params_a), (b, parse_param_set(&config, b)?)],
+                            prompt,
+                        ))
+                    })
+                }
+                _ => Err("Usage: /compareparams <params> | <params> | <prompt>, e.g. /compareparams temperature=0.2 | temperature=0.8 | Write a haiku. A model with a parameter profile can stand in for the params.".to_string()),
+            };
+            let cancel = state.cancellation_token(msg.chat.id);
+            let message = match parsed {
+                Ok((sets, prompt)) => {
+                    compare_params(&bot, &msg, &config, &state, &sets, prompt, &cancel)
+                        .await
+                        .unwrap_or_else(|e| e)
                 }
+                Err(e) => e,
             };
 
-            let response = match parsed_response["choices"][0]["message"]["content"].as_str() {
-                Some(response) => response,
-                None => {
-                    error!("Error parsing response: {:?}", parsed_response);
-                    bot.send_message(msg.chat.id, "An error occurred while parsing the response.")
-                        .reply_to_message_id(msg.id)
-                        .await?;
-                    return Ok(());
+            return send_long_message(&bot, &msg, &config, &state, None, &message, &cancel).await;
+        }
+        Command::Bench => {
+            let request_id = state.next_request_id();
+            Span::current().record("request_id", request_id);
+            info!("Received benchmark request #{}", request_id);
+
+            let cancel = state.cancellation_token(msg.chat.id);
+            let message =
+                match run_benchmark(&bot, &msg, &config, &state, request_id, &cancel).await {
+                    Ok(results) => format_benchmark(&results),
+                    Err(e) => e,
+                };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Echo(text) if text.trim().is_empty() => {
+            reply(&bot, &msg, &config, "Usage: /echo <text>").await?
+        }
+        Command::Echo(text) => {
+            // Goes through the same splitting and code file handling as the model's answers
+            let cancel = state.cancellation_token(msg.chat.id);
+            return send_long_message(&bot, &msg, &config, &state, None, &text, &cancel).await;
+        }
+        Command::Reload => {
+            let message = match Config::reload() {
+                Ok(new_config) => {
+                    let changed = config.changed_fields(&new_config);
+                    load_saved_state(Some(&config), &new_config, &state);
+                    *shared_config.write().unwrap() = new_config;
+                    if changed.is_empty() {
+                        info!("Reloaded the config, nothing changed");
+                        "Reloaded the config, nothing changed.".to_string()
+                    } else {
+                        info!("Reloaded the config, changed: {}", changed.join(", "));
+                        let restart: Vec<_> = changed
+                            .iter()
+                            .filter(|field| Config::needs_restart(field))
+                            .map(String::as_str)
+                            .collect();
+                        let mut message =
+                            format!("Reloaded the config, changed: {}", changed.join(", "));
+                        if !restart.is_empty() {
+                            message.push_str(&format!(
+                                "\n\nThese only take effect after a restart: {}",
+                                restart.join(", ")
+                            ));
+                        }
+                        message
+                    }
+                }
+                Err(problems) => {
+                    warn!(
+                        "Not reloading the config, it has problems:\n{}",
+                        problems.join("\n")
+                    );
+                    format!(
+                        "The config has problems, keeping the old one:\n{}",
+                        problems.join("\n")
+                    )
                 }
             };
 
-            info!("Response: {}", response);
-            bot.send_message(msg.chat.id, response)
-                .reply_to_message_id(msg.id)
-                .await?
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Threads(args) => {
+            let args = args.trim();
+            let message = if args.is_empty() {
+                match fetch_props().await {
+                    Some(props) => match props_threads(&props) {
+                        Some(threads) => format!("The server uses {} threads.", threads),
+                        None => "The server doesn't report its thread count.".to_string(),
+                    },
+                    None => "Couldn't reach the server.".to_string(),
+                }
+            } else {
+                match args.parse::<u64>() {
+                    Ok(threads) if threads > 0 => {
+                        info!("Changing the server threads to {}", threads);
+                        if set_server_threads(threads).await {
+                            format!("The server now uses {} threads.", threads)
+                        } else {
+                            format!("The server doesn't support changing the thread count while it's running, restart it with --threads {} instead.", threads)
+                        }
+                    }
+                    _ => "Invalid number of threads, e.g. /threads 3".to_string(),
+                }
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::LogLevel(args) => {
+            let message = match args.trim() {
+                "" => match LOG_FILTER
+                    .get()
+                    .map(|handle| handle.with_current(|f| f.to_string()))
+                {
+                    Some(Ok(filter)) => format!("The log filter is {:?}.", filter),
+                    _ => "Couldn't read the log filter.".to_string(),
+                },
+                args => {
+                    // `reset` goes back to RUST_LOG from the environment
+                    let filter = if args == "reset" {
+                        Ok(EnvFilter::from_default_env())
+                    } else {
+                        EnvFilter::try_new(args)
+                    };
+                    match filter {
+                        Ok(filter) => {
+                            let shown = filter.to_string();
+                            match LOG_FILTER.get().map(|handle| handle.reload(filter)) {
+                                Some(Ok(())) => {
+                                    info!("Changed the log filter to {:?}", shown);
+                                    format!("The log filter is now {:?}.", shown)
+                                }
+                                _ => "Couldn't change the log filter.".to_string(),
+                            }
+                        }
+                        Err(e) => format!(
+                            "Invalid filter: {}\nE.g. /loglevel debug or /loglevel info,rpi_zero_llm_bot=debug",
+                            e
+                        ),
+                    }
+                }
+            };
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Metrics => {
+            let message = format!(
+                "Typing indicators: {}\nFree request slots: {}/{}\nGroups joined since the restart: {}",
+                TypingIndicator::active(),
+                state.free_completion_permits(),
+                config.max_concurrent_requests,
+                state.joined_groups().len()
+            );
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Diag => {
+            info!("Checking {} backends", BACKENDS.len());
+            let checks = futures_util::future::join_all(
+                BACKENDS
+                    .iter()
+                    .map(|&(name, url)| check_backend(name, url, &config.health_path)),
+            )
+            .await;
+
+            reply_markdown(&bot, &msg, &config, format_backend_checks(&checks)).await?
+        }
+        Command::Diagnose => {
+            info!("Running the self-checks");
+            let (health, api_key) =
+                tokio::join!(check_server_health(&config), check_api_key(&config));
+            let mut checks = Vec::from(health);
+            checks.push(api_key);
+            checks.push(check_daily_limit_file(&config));
+
+            reply(&bot, &msg, &config, format_diagnose_checks(&checks)).await?
+        }
+        Command::Version => {
+            let (server_version, model) = fetch_server_info().await;
+            let message = format!(
+                "Bot version: {}\nServer version: {}\nModel: {}",
+                env!("CARGO_PKG_VERSION"),
+                server_version.as_deref().unwrap_or("unknown"),
+                model.as_deref().unwrap_or("unknown")
+            );
+
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Stop => {
+            info!("Stopping requests in {}", msg.chat.id);
+            state.stop_requests(msg.chat.id);
+            reply(&bot, &msg, &config, "Stopped.").await?
+        }
+        Command::Pause => {
+            info!("Pausing the bot");
+            state.set_paused(true);
+            let message = if config.pause_aborts_requests {
+                info!("Stopping all running requests");
+                state.stop_all_requests();
+                "The bot is paused and the running requests were stopped, new requests will be refused until /resume."
+            } else {
+                "The bot is paused, new requests will be refused until /resume."
+            };
+            reply(&bot, &msg, &config, message).await?
+        }
+        Command::Resume => {
+            info!("Resuming the bot");
+            state.set_paused(false);
+            reply(&bot, &msg, &config, "The bot is accepting requests again.").await?
         }
         Command::Health => {
-            info!("Received health check request");
-            let response = reqwest::get(&format!("{}/health", URL)).await;
+            let request_id = state.next_request_id();
+            Span::current().record("request_id", request_id);
+            info!("Received health check request #{}", request_id);
+            let response = client()
+                .get(format!("{}{}", URL, config.health_path))
+                .send()
+                .await;
             let response = match response {
                 Ok(response) => response,
                 Err(e) => {
                     error!("Error sending health check request: {}", e);
-                    bot.send_message(
-                        msg.chat.id,
+                    state.record_error(
+                        request_id,
+                        format!("Error sending health check request: {}", e),
+                    );
+                    react_to_health(&bot, &msg, &config, HEALTH_ERROR_REACTION).await;
+                    let sent = reply(
+                        &bot,
+                        &msg,
+                        &config,
                         "An error occurred while sending the health check request.",
                     )
-                    .reply_to_message_id(msg.id)
                     .await?;
+                    state.track_sent_message(&sent);
                     return Ok(());
                 }
             };
@@ -193,49 +3702,367 @@ async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
                 }
                 Err(e) => {
                     error!("Error reading health check response: {}", e);
-                    bot.send_message(
-                        msg.chat.id,
+                    state.record_error(
+                        request_id,
+                        format!("Error reading health check response: {}", e),
+                    );
+                    react_to_health(&bot, &msg, &config, HEALTH_ERROR_REACTION).await;
+                    let sent = reply(
+                        &bot,
+                        &msg,
+                        &config,
                         "An error occurred while reading the health check response.",
                     )
-                    .reply_to_message_id(msg.id)
                     .await?;
+                    state.track_sent_message(&sent);
                     return Ok(());
                 }
             };
 
-            let message = match status {
-                StatusCode::OK => {
-                    let health: HealthResponse = serde_json::from_str(&body).unwrap();
-                    match health.status.as_str() {
-                        "ok" => format!("Everything is working fine. Slots idle: {}, Slots processing: {}", health.slots_idle.unwrap_or(0), health.slots_processing.unwrap_or(0)),
-                        "no slot available" => format!("No slots are currently available. Slots idle: {}, Slots processing: {}", health.slots_idle.unwrap_or(0), health.slots_processing.unwrap_or(0)),
-                        _ => format!("Unknown status: {}", health.status),
-                    }
-                }
-                StatusCode::SERVICE_UNAVAILABLE => {
-                    let health: HealthResponse = serde_json::from_str(&body).unwrap();
-                    match health.status.as_str() {
-                        "loading model" => "The model is still being loaded. Please wait.".to_string(),
-                        "no slot available" => format!("No slots are currently available. Slots idle: {}, Slots processing: {}", health.slots_idle.unwrap_or(0), health.slots_processing.unwrap_or(0)),
-                        _ => format!("Unknown status: {}", health.status),
-                    }
-                }
-                StatusCode::INTERNAL_SERVER_ERROR => {
-                    let health: HealthResponse = serde_json::from_str(&body).unwrap();
-                    match health.status.as_str() {
-                        "error" => "An error occurred while loading the model.".to_string(),
-                        _ => format!("Unknown status: {}", health.status),
-                    }
-                }
-                _ => format!("Unexpected status: {}", status),
-            };
+            let message = build_health_message(status, &body);
 
             info!("Health check response: {}", message);
-            bot.send_message(msg.chat.id, message)
-                .reply_to_message_id(msg.id)
-                .await?
+            react_to_health(&bot, &msg, &config, health_reaction(status, &body)).await;
+            reply(&bot, &msg, &config, message).await?
+        }
+    };
+    state.track_sent_message(&sent);
+
+    Ok(())
+}
+
+/// The `/health` reply for the server's answer. Anything unexpected is described instead of panicking,
+/// a broken server is exactly when someone asks for its health.
+fn build_health_message(status: StatusCode, body: &str) -> String {
+    if !matches!(
+        status,
+        StatusCode::OK | StatusCode::SERVICE_UNAVAILABLE | StatusCode::INTERNAL_SERVER_ERROR
+    ) {
+        return format!("Unexpected status: {}", status);
+    }
+    let Ok(health) = serde_json::from_str::<HealthResponse>(body) else {
+        return format!(
+            "The server answered {} with an unreadable health status.",
+            status
+        );
+    };
+    let slots = format!(
+        "Slots idle: {}, Slots processing: {}",
+        health.slots_idle.unwrap_or(0),
+        health.slots_processing.unwrap_or(0)
+    );
+    match (status, health.status.as_str()) {
+        (StatusCode::OK, "ok") => format!("Everything is working fine. {}", slots),
+        (StatusCode::OK | StatusCode::SERVICE_UNAVAILABLE, "no slot available") => {
+            format!("No slots are currently available. {}", slots)
+        }
+        (StatusCode::SERVICE_UNAVAILABLE, "loading model") => {
+            "The model is still being loaded. Please wait.".to_string()
+        }
+        (StatusCode::INTERNAL_SERVER_ERROR, "error") => {
+            "An error occurred while loading the model.".to_string()
         }
+        _ => format!("Unknown status: {}", health.status),
+    }
+}
+
+/// The `/health` reaction for the server's answer. A full server is still working fine.
+fn health_reaction(status: StatusCode, body: &str) -> &'static str {
+    let health = serde_json::from_str::<HealthResponse>(body).ok();
+    match (status, health.as_ref().map(|health| health.status.as_str())) {
+        (StatusCode::OK, _) | (_, Some("no slot available")) => HEALTH_OK_REACTION,
+        (_, Some("loading model")) => HEALTH_LOADING_REACTION,
+        _ => HEALTH_ERROR_REACTION,
+    }
+}
+
+/// Reacts to the `/health` command with `emoji`, if `HEALTH_REACTIONS` is on.
+async fn react_to_health(bot: &Bot, msg: &Message, config: &Config, emoji: &str) {
+    if config.health_reactions {
+        set_reaction(bot, msg, emoji).await;
+    }
+}
+
+/// Sets the bot's reaction to `msg`. teloxide 0.12 doesn't know `setMessageReaction` yet, so the
+/// Bot API is called directly. Failures are only logged, e.g. in chats that don't allow reactions.
+async fn set_reaction(bot: &Bot, msg: &Message, emoji: &str) {
+    let Ok(url) = bot
+        .api_url()
+        .join(&format!("/bot{}/setMessageReaction", bot.token()))
+    else {
+        warn!("Couldn't build the setMessageReaction URL");
+        return;
+    };
+    let body = serde_json::json!({
+        "chat_id": msg.chat.id.0,
+        "message_id": msg.id.0,
+        "reaction": [{ "type": "emoji", "emoji": emoji }],
+    });
+    // The URL contains the bot token, so it's left out of the errors
+    match bot.client().post(url).json(&body).send().await {
+        Ok(response) => match response.json::<Value>().await {
+            Ok(response) if response["ok"].as_bool() == Some(true) => {}
+            Ok(response) => warn!(
+                "Couldn't set the reaction: {}",
+                response["description"].as_str().unwrap_or("unknown error")
+            ),
+            Err(e) => warn!("Couldn't read the reaction response: {}", e.without_url()),
+        },
+        Err(e) => warn!("Couldn't set the reaction: {}", e.without_url()),
+    }
+}
+
+/// Handles the buttons of the `/history` browser: flips to another prompt, or runs one again
+/// as a reply to the browser, the same way as `/qwen`.
+#[instrument(skip_all, fields(user = %query.from.id))]
+async fn history_button_pressed(
+    bot: Bot,
+    query: CallbackQuery,
+    config: Arc<RwLock<Config>>,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let data = query.data.as_deref().unwrap_or_default();
+    let parsed = data.split_once(':').and_then(|(action, rest)| {
+        let (user_id, value) = rest.split_once(':')?;
+        Some((action, user_id.parse::<u64>().ok()?, value))
+    });
+    let Some((action, user_id, value)) = parsed else {
+        debug!("Ignoring unknown button data {:?}", data);
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    };
+    if user_id != query.from.id.0 {
+        bot.answer_callback_query(query.id)
+            .text("That's someone else's history, send /history to see yours.")
+            .await?;
+        return Ok(());
+    }
+    let Some(message) = query.message else {
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
     };
 
+    let prompts = state.search_prompts(query.from.id, "");
+    match action {
+        "history" => {
+            bot.answer_callback_query(query.id).await?;
+            let Some(position) = value
+                .parse::<usize>()
+                .ok()
+                .filter(|position| *position < prompts.len())
+            else {
+                return Ok(());
+            };
+            let edit = bot
+                .edit_message_text(
+                    message.chat.id,
+                    message.id,
+                    format_history_page(&prompts, position),
+                )
+                .reply_markup(history_keyboard(query.from.id, &prompts, position))
+                .await;
+            if let Err(e) = edit {
+                warn!("Couldn't show another page of the history: {}", e);
+            }
+        }
+        "rerun" => {
+            let entry = prompts
+                .into_iter()
+                .find(|entry| entry.time.timestamp_millis().to_string() == value);
+            let Some(entry) = entry else {
+                bot.answer_callback_query(query.id)
+                    .text("That prompt isn't in your history anymore.")
+                    .await?;
+                return Ok(());
+            };
+            bot.answer_callback_query(query.id).await?;
+            info!("Running a prompt from the history again");
+            // The browser stands in for the command message, sent by whoever pressed the button
+            let mut msg = message;
+            if let MessageKind::Common(common) = &mut msg.kind {
+                common.from = Some(query.from);
+                common.reply_to_message = None;
+            }
+            return answer(bot, msg, Command::Qwen(entry.prompt), config, state).await;
+        }
+        _ => {
+            debug!("Ignoring unknown button action {:?}", action);
+            bot.answer_callback_query(query.id).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Greets and records groups the bot gets added to and cleans up after it gets removed from one.
+#[instrument(skip_all, fields(chat = %update.chat.id))]
+async fn my_chat_member_changed(
+    bot: Bot,
+    update: ChatMemberUpdated,
+    config: Arc<RwLock<Config>>,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let config = config.read().unwrap().clone();
+    let chat_id = update.chat.id;
+    let was_present = update.old_chat_member.is_present();
+    let is_present = update.new_chat_member.is_present();
+
+    if is_present && !was_present && (update.chat.is_group() || update.chat.is_supergroup()) {
+        info!("Added to group {}", chat_id);
+        state.record_joined_group(chat_id);
+        bot.send_message(
+            chat_id,
+            format!("{}\n\n{}", config.greeting, Command::descriptions()),
+        )
+        .disable_web_page_preview(!config.link_previews)
+        .await?;
+    } else if was_present && !is_present {
+        info!("Removed from chat {}, forgetting its state", chat_id);
+        state.forget_chat(chat_id);
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_commands_lose_the_bot_mention() {
+        // teloxide strips the mention glued to the command itself
+        let Ok(Command::Qwen(mut prompt)) = Command::parse("/qwen@mybot hello", "mybot") else {
+            panic!("not parsed as /qwen");
+        };
+        assert_eq!(prompt, "hello");
+        strip_bot_mention(&mut prompt, "mybot");
+        assert_eq!(prompt, "hello");
+
+        // but not one after a space
+        let Ok(Command::Qwen(mut prompt)) = Command::parse("/qwen @MyBot hello", "mybot") else {
+            panic!("not parsed as /qwen");
+        };
+        assert_eq!(prompt, "@MyBot hello");
+        strip_bot_mention(&mut prompt, "mybot");
+        assert_eq!(prompt, "hello");
+    }
+
+    #[test]
+    fn other_mentions_stay_in_the_prompt() {
+        for text in [
+            "@mybot2 hello",
+            "@someone hello",
+            "hello @mybot",
+            "mybot hello",
+        ] {
+            let mut prompt = text.to_string();
+            strip_bot_mention(&mut prompt, "mybot");
+            assert_eq!(prompt, text);
+        }
+    }
+
+    #[test]
+    fn health_ok() {
+        assert_eq!(
+            build_health_message(
+                StatusCode::OK,
+                r#"{"status": "ok", "slots_idle": 1, "slots_processing": 0}"#
+            ),
+            "Everything is working fine. Slots idle: 1, Slots processing: 0"
+        );
+        // Newer llama.cpp versions leave the slots out
+        assert_eq!(
+            build_health_message(StatusCode::OK, r#"{"status": "ok"}"#),
+            "Everything is working fine. Slots idle: 0, Slots processing: 0"
+        );
+    }
+
+    #[test]
+    fn health_no_slot_available() {
+        let body = r#"{"status": "no slot available", "slots_idle": 0, "slots_processing": 2}"#;
+        let expected = "No slots are currently available. Slots idle: 0, Slots processing: 2";
+        assert_eq!(build_health_message(StatusCode::OK, body), expected);
+        assert_eq!(
+            build_health_message(StatusCode::SERVICE_UNAVAILABLE, body),
+            expected
+        );
+    }
+
+    #[test]
+    fn health_loading_model_and_error() {
+        assert_eq!(
+            build_health_message(
+                StatusCode::SERVICE_UNAVAILABLE,
+                r#"{"status": "loading model"}"#
+            ),
+            "The model is still being loaded. Please wait."
+        );
+        assert_eq!(
+            build_health_message(StatusCode::INTERNAL_SERVER_ERROR, r#"{"status": "error"}"#),
+            "An error occurred while loading the model."
+        );
+    }
+
+    #[test]
+    fn health_unknown_status() {
+        assert_eq!(
+            build_health_message(StatusCode::OK, r#"{"status": "sleeping"}"#),
+            "Unknown status: sleeping"
+        );
+        assert_eq!(
+            build_health_message(StatusCode::INTERNAL_SERVER_ERROR, r#"{"status": "ok"}"#),
+            "Unknown status: ok"
+        );
+        assert_eq!(
+            build_health_message(StatusCode::NOT_FOUND, "Not Found"),
+            "Unexpected status: 404 Not Found"
+        );
+    }
+
+    #[test]
+    fn health_malformed_body() {
+        for status in [
+            StatusCode::OK,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            assert_eq!(
+                build_health_message(status, "<html>Bad Gateway</html>"),
+                format!(
+                    "The server answered {} with an unreadable health status.",
+                    status
+                )
+            );
+        }
+        assert!(build_health_message(StatusCode::OK, "").contains("unreadable"));
+    }
+
+    #[test]
+    fn repetition_below_the_limit_is_kept() {
+        assert_eq!(trim_repetition("Yes. Yes. Yes. No.", 3), None);
+        assert_eq!(trim_repetition("Yes. Yes. Yes. Yes.", 0), None);
+        // The unterminated tail isn't a full repeat yet
+        assert_eq!(trim_repetition("Yes. Yes. Yes. Yes", 3), None);
+    }
+
+    #[test]
+    fn repetition_above_the_limit_is_cut() {
+        assert_eq!(
+            trim_repetition("Sure! Hello.\nHello.\nHello.\nHello.\nHello.", 3),
+            Some("Sure! Hello.".to_string())
+        );
+        // Blank lines and ellipses between the repeats don't hide them
+        assert_eq!(
+            trim_repetition("Hi!\n\nHi!\n...\nHi!", 2),
+            Some("Hi!".to_string())
+        );
+    }
+
+    #[test]
+    fn dots_in_numbers_dont_end_sentences() {
+        assert_eq!(trim_repetition("Update to 1.1.1.1.1 now.", 2), None);
+        assert_eq!(
+            trim_repetition("Pi is 3.14. Pi is 3.14.", 1),
+            Some("Pi is 3.14.".to_string())
+        );
+    }
+}
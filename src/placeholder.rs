@@ -0,0 +1,188 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use teloxide::{prelude::*, types::MessageId, ApiError, RequestError};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn, Instrument};
+
+use crate::config::Config;
+
+// Longest the animation slows down to when Telegram keeps rate limiting the edits
+const MAX_INTERVAL: Duration = Duration::from_secs(10);
+
+// Answers often take longer than the average, so the estimate never claims to be done
+const MAX_PROGRESS_PERCENT: u128 = 95;
+
+/// A "thinking..." message that keeps cycling through its frames until it's replaced with the answer.
+/// This way users can see the bot is still alive, even though the Pi takes a while to respond.
+pub struct Placeholder {
+    chat_id: ChatId,
+    message_id: MessageId,
+    animation: Option<JoinHandle<()>>,
+    link_previews: bool,
+    // `None` unless retries are shown to the user
+    retrying: Option<Arc<AtomicBool>>,
+}
+
+/// Lets the request tell the user it's retrying, the placeholder's frames say so until it's
+/// answered.
+#[derive(Clone)]
+pub struct RetryNotice(Arc<AtomicBool>);
+
+impl RetryNotice {
+    pub fn set(&self, retrying: bool) {
+        self.0.store(retrying, Ordering::Relaxed);
+    }
+}
+
+impl Placeholder {
+    /// Replies to `msg` with the first frame and starts animating it.
+    /// Returns `None` if placeholders are disabled or the message couldn't be sent.
+    ///
+    /// With an `expected` generation time, the frames also show an estimate of how far along
+    /// the answer is.
+    ///
+    /// If someone deletes the placeholder the next edit fails and `cancel` gets cancelled,
+    /// so the Pi doesn't keep generating an answer nobody will see.
+    pub async fn start(
+        bot: &Bot,
+        msg: &Message,
+        config: &Config,
+        expected: Option<Duration>,
+        cancel: CancellationToken,
+    ) -> Option<Self> {
+        let first_frame = config.placeholder_frames.first()?;
+        let message = match crate::reply(bot, msg, config, first_frame).await {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Couldn't send the placeholder: {}", e);
+                return None;
+            }
+        };
+
+        // A single frame doesn't need animating unless the progress or the retry note changes,
+        // editing it to the same text would just fail
+        let started = Instant::now();
+        let retrying = config
+            .show_retries
+            .then(|| Arc::new(AtomicBool::new(false)));
+        let animation = (config.placeholder_frames.len() > 1
+            || expected.is_some()
+            || retrying.is_some())
+        .then(|| {
+            let bot = bot.clone();
+            let frames = config.placeholder_frames.clone();
+            let base_interval = config.placeholder_interval;
+            let (chat_id, message_id) = (message.chat.id, message.id);
+            let mut last_text = first_frame.clone();
+            let retrying = retrying.clone();
+            tokio::spawn(async move {
+                let mut interval = base_interval;
+                for frame in frames.iter().cycle().skip(1) {
+                    tokio::time::sleep(interval).await;
+                    let mut text = with_progress(frame, started.elapsed(), expected);
+                    if retrying.as_ref().is_some_and(|retrying| retrying.load(Ordering::Relaxed)) {
+                        text.push_str(" (retrying...)");
+                    }
+                    if text == last_text {
+                        continue;
+                    }
+                    last_text = text.clone();
+                    match bot.edit_message_text(chat_id, message_id, text).await {
+                        // Slowly go back to the configured speed once Telegram stops complaining
+                        Ok(_) => interval = (interval * 3 / 4).max(base_interval),
+                        // Skip the frames we missed instead of catching up, and edit less often from now on
+                        Err(RequestError::RetryAfter(retry_after)) => {
+                            interval = (interval * 2).min(MAX_INTERVAL);
+                            debug!(
+                                "Placeholder edits are rate limited, waiting {}s and slowing down to one every {}ms",
+                                retry_after.as_secs(),
+                                interval.as_millis()
+                            );
+                            tokio::time::sleep(retry_after).await;
+                        }
+                        Err(RequestError::Api(ApiError::MessageToEditNotFound)) => {
+                            info!("Placeholder was deleted, cancelling the request");
+                            cancel.cancel();
+                            break;
+                        }
+                        Err(e) => debug!("Couldn't update the placeholder: {}", e),
+                    }
+                }
+            }.in_current_span())
+        });
+
+        Some(Self {
+            chat_id: message.chat.id,
+            message_id: message.id,
+            animation,
+            link_previews: config.link_previews,
+            retrying,
+        })
+    }
+
+    /// A handle for showing retries on this placeholder, `None` if `SHOW_RETRIES` is off.
+    pub fn retry_notice(&self) -> Option<RetryNotice> {
+        self.retrying.clone().map(RetryNotice)
+    }
+
+    /// Stops the animation and replaces the placeholder with `text`.
+    pub async fn finish(self, bot: &Bot, text: String) -> ResponseResult<Message> {
+        if let Some(animation) = &self.animation {
+            animation.abort();
+        }
+        let edit = bot
+            .edit_message_text(self.chat_id, self.message_id, text)
+            .disable_web_page_preview(!self.link_previews);
+        match edit.clone().await {
+            // The answer is the one edit that can't be skipped, so wait until Telegram allows it
+            Err(RequestError::RetryAfter(retry_after)) => {
+                info!(
+                    "Rate limited while sending the answer, retrying in {}s",
+                    retry_after.as_secs()
+                );
+                tokio::time::sleep(retry_after).await;
+                edit.await
+            }
+            result => result,
+        }
+    }
+
+    /// Stops the animation and deletes the placeholder, for answers that aren't text.
+    pub async fn remove(self, bot: &Bot) {
+        if let Some(animation) = &self.animation {
+            animation.abort();
+        }
+        match bot.delete_message(self.chat_id, self.message_id).await {
+            Ok(_) | Err(RequestError::Api(ApiError::MessageToDeleteNotFound)) => {}
+            Err(e) => warn!("Couldn't delete the placeholder: {}", e),
+        }
+    }
+}
+
+/// Appends the estimated progress to `frame`, e.g. "thinking... (~60% done)".
+fn with_progress(frame: &str, elapsed: Duration, expected: Option<Duration>) -> String {
+    match expected.filter(|expected| !expected.is_zero()) {
+        Some(expected) => {
+            let percent =
+                (elapsed.as_millis() * 100 / expected.as_millis().max(1)).min(MAX_PROGRESS_PERCENT);
+            format!("{} (~{}% done)", frame, percent)
+        }
+        None => frame.to_string(),
+    }
+}
+
+impl Drop for Placeholder {
+    // Makes sure the animation doesn't outlive the request, even if it never got finished
+    fn drop(&mut self) {
+        if let Some(animation) = &self.animation {
+            animation.abort();
+        }
+    }
+}
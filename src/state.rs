@@ -0,0 +1,617 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use teloxide::types::{ChatId, Message, MessageId, UserId};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_util::sync::CancellationToken;
+
+// The Pi only has 512MB of RAM, so keep just enough errors to see what went wrong recently
+const MAX_RECORDED_ERRORS: usize = 20;
+
+// How many of its own messages per chat the bot remembers for /clear
+const MAX_TRACKED_MESSAGES: usize = 50;
+
+// Commands edited after this many newer ones run again even if they didn't change
+const MAX_TRACKED_COMMANDS: usize = 200;
+
+// Enough to find last week's question without growing forever
+const MAX_RECORDED_PROMPTS: usize = 50;
+
+// Recent enough to follow a model or settings change, long enough to smooth out odd answers
+const MAX_RECORDED_GENERATION_TIMES: usize = 20;
+
+/// How conversations are sent to the model, picked with `/mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PromptFormat {
+    /// A messages array for `/v1/chat/completions`, the server applies the chat template.
+    #[default]
+    Chat,
+    /// A prompt with the instruct template already applied, for `/v1/completions`.
+    Instruct,
+}
+
+/// How answers should be structured, picked with `/format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnswerFormat {
+    Bullets,
+    Prose,
+}
+
+impl AnswerFormat {
+    /// The instruction added to the system prompt.
+    pub fn instruction(self) -> &'static str {
+        match self {
+            AnswerFormat::Bullets => "Format your answers as a short list of bullet points.",
+            AnswerFormat::Prose => "Answer in plain paragraphs, without lists or headings.",
+        }
+    }
+}
+
+/// Per-chat overrides set through bot commands.
+#[derive(Debug, Clone, Default)]
+pub struct ChatSettings {
+    /// Token id and bias pairs sent as `logit_bias`, left out of the request when empty.
+    pub logit_bias: Vec<(u32, f32)>,
+    /// Overrides how many history turns are remembered for this chat.
+    pub history_turns: Option<usize>,
+    /// Turns the history on or off for this chat, `None` uses `HISTORY_ENABLED`.
+    pub history_enabled: Option<bool>,
+    /// Whether the conversation is sent as messages or as an instruct prompt.
+    pub prompt_format: PromptFormat,
+    /// Model (or alias) picked with `/model`.
+    pub model: Option<String>,
+    /// Overrides the `max_tokens` cap for this chat.
+    pub max_tokens: Option<u32>,
+    /// Overrides the response time budget for this chat, `Some(0)` turns it off.
+    pub time_budget_secs: Option<u64>,
+    /// Answers longer than this many characters are cut off, set with `/maxlen`.
+    pub max_length: Option<usize>,
+    /// Structure asked for in the system prompt, `None` leaves it up to the model.
+    pub answer_format: Option<AnswerFormat>,
+    /// Persona picked with `/persona`, its system prompt is sent before the conversation.
+    pub persona: Option<String>,
+    /// Don't show "typing..." while waiting for the model, set with `/typing off`.
+    pub hide_typing: bool,
+    /// Condense the turns dropped from the history into a summary instead of forgetting them.
+    pub summarize_history: bool,
+}
+
+/// One prompt and the model's answer to it.
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub prompt: String,
+    pub response: String,
+}
+
+/// What's left of the turns dropped from a chat's history, with `/summary` on.
+#[derive(Debug, Default)]
+struct HistorySummary {
+    /// The model's summary of the turns summarized so far.
+    text: Option<String>,
+    /// Dropped turns that aren't in the summary yet.
+    pending: Vec<Turn>,
+}
+
+/// A message the bot sent, deletable with `/clear`.
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub id: MessageId,
+    pub time: DateTime<Utc>,
+}
+
+/// A prompt someone sent, searchable with `/history`.
+#[derive(Debug, Clone)]
+pub struct PromptEntry {
+    pub time: DateTime<Utc>,
+    pub prompt: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ErrorEntry {
+    pub time: DateTime<Utc>,
+    pub request_id: u64,
+    pub message: String,
+    /// What the server said it was doing, filled in after the error is recorded.
+    pub diagnostics: Arc<OnceLock<String>>,
+}
+
+/// A request's place in the queue for a completion permit.
+pub struct QueueTicket<'a> {
+    queue: &'a Mutex<BTreeSet<u64>>,
+    id: u64,
+}
+
+impl QueueTicket<'_> {
+    /// 1 for the request that gets the next free permit. Goes down as the requests ahead of it
+    /// get their turn or give up.
+    pub fn position(&self) -> usize {
+        self.queue.lock().unwrap().range(..=self.id).count()
+    }
+}
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// State shared between all handlers. Everything is kept in memory and lost on restart.
+#[derive(Debug)]
+pub struct State {
+    chats: Mutex<HashMap<ChatId, ChatSettings>>,
+    histories: Mutex<HashMap<ChatId, VecDeque<Turn>>>,
+    summaries: Mutex<HashMap<ChatId, HistorySummary>>,
+    errors: Mutex<VecDeque<ErrorEntry>>,
+    /// The bot's latest messages in each chat, oldest first.
+    sent_messages: Mutex<HashMap<ChatId, VecDeque<SentMessage>>>,
+    /// Past prompts of each user, kept per user so nobody can search someone else's.
+    prompts: Mutex<HashMap<UserId, VecDeque<PromptEntry>>>,
+    last_request_id: AtomicU64,
+    paused: AtomicBool,
+    /// Parent token of the running requests in each chat, cancelled by `/stop`.
+    stop_tokens: Mutex<HashMap<ChatId, CancellationToken>>,
+    /// Limits how many requests are sent to the model at once, cheap commands don't need a permit.
+    completion_permits: Semaphore,
+    /// Tickets of the requests waiting for a permit, in the order they came in.
+    queue: Mutex<BTreeSet<u64>>,
+    last_ticket: AtomicU64,
+    /// When the last model request started, for unloading the model when the bot is idle.
+    last_activity: Mutex<Instant>,
+    /// Whether the idle unload command ran and the model has to be woken up again.
+    model_unloaded: AtomicBool,
+    /// The day requests are counted for the daily limit, and how many were made that day.
+    daily_requests: Mutex<(NaiveDate, u32)>,
+    /// How long the latest finished answers took to generate, for the placeholder's progress estimate.
+    generation_times: Mutex<VecDeque<Duration>>,
+    /// Hash of the command last run for a message, newest last, so an edit that doesn't change
+    /// the command doesn't run it again.
+    message_commands: Mutex<VecDeque<((ChatId, MessageId), u64)>>,
+    /// Users who already got the welcome notice.
+    welcomed: Mutex<HashSet<UserId>>,
+    /// Groups the bot was added to, for broadcasts.
+    joined_groups: Mutex<HashSet<ChatId>>,
+    /// When the admins were last told that the server is offline.
+    last_offline_alert: Mutex<Option<Instant>>,
+}
+
+impl State {
+    pub fn new(max_concurrent_requests: usize) -> Self {
+        Self {
+            chats: Mutex::default(),
+            histories: Mutex::default(),
+            summaries: Mutex::default(),
+            errors: Mutex::default(),
+            sent_messages: Mutex::default(),
+            prompts: Mutex::default(),
+            last_request_id: AtomicU64::default(),
+            paused: AtomicBool::default(),
+            stop_tokens: Mutex::default(),
+            completion_permits: Semaphore::new(max_concurrent_requests),
+            queue: Mutex::default(),
+            last_ticket: AtomicU64::default(),
+            last_activity: Mutex::new(Instant::now()),
+            model_unloaded: AtomicBool::default(),
+            daily_requests: Mutex::default(),
+            generation_times: Mutex::default(),
+            message_commands: Mutex::default(),
+            welcomed: Mutex::default(),
+            joined_groups: Mutex::default(),
+            last_offline_alert: Mutex::default(),
+        }
+    }
+
+    pub fn chat_settings(&self, chat_id: ChatId) -> ChatSettings {
+        self.chats
+            .lock()
+            .unwrap()
+            .get(&chat_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn update_chat_settings(&self, chat_id: ChatId, update: impl FnOnce(&mut ChatSettings)) {
+        update(self.chats.lock().unwrap().entry(chat_id).or_default());
+    }
+
+    /// The last `max_turns` turns of the chat's conversation history, oldest first.
+    pub fn history(&self, chat_id: ChatId, max_turns: usize) -> Vec<Turn> {
+        self.histories
+            .lock()
+            .unwrap()
+            .get(&chat_id)
+            .map(|history| {
+                let skip = history.len().saturating_sub(max_turns);
+                history.iter().skip(skip).cloned().collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Appends a turn to the chat's history, keeping at most `max_turns` of them.
+    /// Returns the turns that didn't fit anymore.
+    pub fn push_turn(&self, chat_id: ChatId, turn: Turn, max_turns: usize) -> Vec<Turn> {
+        if max_turns == 0 {
+            return Vec::new();
+        }
+        let mut histories = self.histories.lock().unwrap();
+        let history = histories.entry(chat_id).or_default();
+        history.push_back(turn);
+        let excess = history.len().saturating_sub(max_turns);
+        history.drain(..excess).collect()
+    }
+
+    /// Forgets the `count` oldest turns of the chat's history and returns them.
+    pub fn drop_oldest_turns(&self, chat_id: ChatId, count: usize) -> Vec<Turn> {
+        match self.histories.lock().unwrap().get_mut(&chat_id) {
+            Some(history) => history.drain(..count.min(history.len())).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The summary of the turns dropped from the chat's history, if there is one yet.
+    pub fn history_summary(&self, chat_id: ChatId) -> Option<String> {
+        let summaries = self.summaries.lock().unwrap();
+        summaries.get(&chat_id)?.text.clone()
+    }
+
+    /// Queues dropped turns for the next summary, returning how many are waiting now.
+    pub fn add_dropped_turns(&self, chat_id: ChatId, turns: Vec<Turn>) -> usize {
+        let mut summaries = self.summaries.lock().unwrap();
+        let pending = &mut summaries.entry(chat_id).or_default().pending;
+        pending.extend(turns);
+        pending.len()
+    }
+
+    /// Takes the dropped turns waiting to be summarized.
+    pub fn take_dropped_turns(&self, chat_id: ChatId) -> Vec<Turn> {
+        let mut summaries = self.summaries.lock().unwrap();
+        summaries
+            .get_mut(&chat_id)
+            .map(|summary| std::mem::take(&mut summary.pending))
+            .unwrap_or_default()
+    }
+
+    /// Replaces the chat's summary, which should now include the turns taken before.
+    pub fn set_history_summary(&self, chat_id: ChatId, text: String) {
+        let mut summaries = self.summaries.lock().unwrap();
+        summaries.entry(chat_id).or_default().text = Some(text);
+    }
+
+    /// Forgets the chat's summary and the turns waiting for it.
+    pub fn clear_history_summary(&self, chat_id: ChatId) {
+        self.summaries.lock().unwrap().remove(&chat_id);
+    }
+
+    /// Forgets the settings and history of a chat, e.g. after the bot was removed from it.
+    pub fn forget_chat(&self, chat_id: ChatId) {
+        self.chats.lock().unwrap().remove(&chat_id);
+        self.histories.lock().unwrap().remove(&chat_id);
+        self.clear_history_summary(chat_id);
+        self.sent_messages.lock().unwrap().remove(&chat_id);
+        self.joined_groups.lock().unwrap().remove(&chat_id);
+        self.stop_requests(chat_id);
+    }
+
+    /// Remembers a group the bot was added to.
+    pub fn record_joined_group(&self, chat_id: ChatId) {
+        self.joined_groups.lock().unwrap().insert(chat_id);
+    }
+
+    /// The groups the bot was added to since the restart and is still in.
+    pub fn joined_groups(&self) -> Vec<ChatId> {
+        self.joined_groups.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Forgets everything stored about a user: their prompts, and the settings, history and sent
+    /// messages of their private chat with the bot. Group chats are shared with others, so they're
+    /// left alone. The recorded errors only hold what the server said, so they're kept.
+    pub fn forget_user(&self, user_id: UserId) {
+        let private_chat = ChatId::from(user_id);
+        self.prompts.lock().unwrap().remove(&user_id);
+        self.chats.lock().unwrap().remove(&private_chat);
+        self.histories.lock().unwrap().remove(&private_chat);
+        self.clear_history_summary(private_chat);
+        self.sent_messages.lock().unwrap().remove(&private_chat);
+        self.welcomed.lock().unwrap().remove(&user_id);
+    }
+
+    /// Returns a token for a new request in the chat, which gets cancelled by `/stop`.
+    /// Cancelling the returned token itself only affects that one request.
+    pub fn cancellation_token(&self, chat_id: ChatId) -> CancellationToken {
+        self.stop_tokens
+            .lock()
+            .unwrap()
+            .entry(chat_id)
+            .or_default()
+            .child_token()
+    }
+
+    /// Cancels every running request in the chat.
+    pub fn stop_requests(&self, chat_id: ChatId) {
+        if let Some(token) = self.stop_tokens.lock().unwrap().remove(&chat_id) {
+            token.cancel();
+        }
+    }
+
+    /// Cancels every running request in every chat.
+    pub fn stop_all_requests(&self) {
+        for (_, token) in self.stop_tokens.lock().unwrap().drain() {
+            token.cancel();
+        }
+    }
+
+    /// Waits until a request can be sent to the model, `None` if `cancel` fires first.
+    pub async fn acquire_completion_permit(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Option<SemaphorePermit<'_>> {
+        tokio::select! {
+            permit = self.completion_permits.acquire() => permit.ok(),
+            _ = cancel.cancelled() => None,
+        }
+    }
+
+    /// Takes a permit if one is free right away.
+    pub fn try_acquire_completion_permit(&self) -> Option<SemaphorePermit<'_>> {
+        self.completion_permits.try_acquire().ok()
+    }
+
+    /// Counts a request as waiting in the queue until the returned ticket is dropped.
+    /// Permits are handed out in order, so the position stays right until it's the request's turn.
+    pub fn enter_queue(&self) -> QueueTicket<'_> {
+        let id = self.last_ticket.fetch_add(1, Ordering::Relaxed);
+        self.queue.lock().unwrap().insert(id);
+        QueueTicket {
+            queue: &self.queue,
+            id,
+        }
+    }
+
+    /// How many more requests can be sent to the model right now.
+    pub fn free_completion_permits(&self) -> usize {
+        self.completion_permits.available_permits()
+    }
+
+    /// Marks the bot as in use right now.
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// How long ago the last model request started.
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    pub fn set_model_unloaded(&self, unloaded: bool) {
+        self.model_unloaded.store(unloaded, Ordering::Relaxed);
+    }
+
+    /// Whether the model was unloaded, resetting the flag so only one request wakes it up.
+    pub fn take_model_unloaded(&self) -> bool {
+        self.model_unloaded.swap(false, Ordering::Relaxed)
+    }
+
+    /// Remembers how long an answer took, dropping the oldest time when there are too many.
+    pub fn record_generation_time(&self, time: Duration) {
+        let mut generation_times = self.generation_times.lock().unwrap();
+        if generation_times.len() == MAX_RECORDED_GENERATION_TIMES {
+            generation_times.pop_front();
+        }
+        generation_times.push_back(time);
+    }
+
+    /// The average time of the latest answers, `None` until one finished.
+    pub fn average_generation_time(&self) -> Option<Duration> {
+        let generation_times = self.generation_times.lock().unwrap();
+        let count = u32::try_from(generation_times.len())
+            .ok()
+            .filter(|&n| n > 0)?;
+        Some(generation_times.iter().sum::<Duration>() / count)
+    }
+
+    /// Counts a request towards the daily limit, starting over on a new day.
+    /// Returns `false` without counting it if `limit` requests were already made `today`.
+    pub fn count_daily_request(&self, today: NaiveDate, limit: u32) -> bool {
+        let mut daily_requests = self.daily_requests.lock().unwrap();
+        if daily_requests.0 != today {
+            *daily_requests = (today, 0);
+        }
+        if daily_requests.1 >= limit {
+            return false;
+        }
+        daily_requests.1 += 1;
+        true
+    }
+
+    /// Whether `limit` requests were already made `today`, without counting one.
+    pub fn daily_limit_reached(&self, today: NaiveDate, limit: u32) -> bool {
+        let daily_requests = self.daily_requests.lock().unwrap();
+        daily_requests.0 == today && daily_requests.1 >= limit
+    }
+
+    /// Writes the daily request count to `path` as `date count`.
+    pub fn save_daily_requests(&self, path: &str) -> std::io::Result<()> {
+        let (day, count) = *self.daily_requests.lock().unwrap();
+        std::fs::write(path, format!("{} {}\n", day, count))
+    }
+
+    /// Reads the daily request count saved by `save_daily_requests`, a missing file is fine.
+    pub fn load_daily_requests(&self, path: &str) -> Result<(), String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        };
+        let parsed = contents.trim().split_once(' ').and_then(|(day, count)| {
+            Some((day.parse::<NaiveDate>().ok()?, count.parse::<u32>().ok()?))
+        });
+        match parsed {
+            Some(daily_requests) => {
+                *self.daily_requests.lock().unwrap() = daily_requests;
+                Ok(())
+            }
+            None => Err(format!("invalid contents {:?}", contents.trim())),
+        }
+    }
+
+    /// Whether the admins should be told that the server is offline, `false` if they already
+    /// were in the last `interval`. Every failed request would otherwise send another message.
+    pub fn take_offline_alert(&self, interval: Duration) -> bool {
+        let mut last_offline_alert = self.last_offline_alert.lock().unwrap();
+        if last_offline_alert.is_some_and(|last| last.elapsed() < interval) {
+            return false;
+        }
+        *last_offline_alert = Some(Instant::now());
+        true
+    }
+
+    /// Remembers that `user_id` got the welcome notice, `false` if they already had.
+    pub fn mark_welcomed(&self, user_id: UserId) -> bool {
+        self.welcomed.lock().unwrap().insert(user_id)
+    }
+
+    /// Writes the welcomed users to `path`, one id per line.
+    pub fn save_welcomed(&self, path: &str) -> std::io::Result<()> {
+        let welcomed = self.welcomed.lock().unwrap();
+        let contents: String = welcomed.iter().map(|user| format!("{}\n", user)).collect();
+        std::fs::write(path, contents)
+    }
+
+    /// Reads the welcomed users saved by `save_welcomed`, a missing file is fine.
+    pub fn load_welcomed(&self, path: &str) -> Result<(), String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        };
+        let welcomed = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.trim().parse().map(UserId))
+            .collect::<Result<HashSet<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        *self.welcomed.lock().unwrap() = welcomed;
+        Ok(())
+    }
+
+    /// Whether an admin paused the bot with `/pause`.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Remembers a message the bot sent, so `/clear` can delete it later.
+    pub fn track_sent_message(&self, message: &Message) {
+        let mut sent_messages = self.sent_messages.lock().unwrap();
+        let sent_messages = sent_messages.entry(message.chat.id).or_default();
+        if sent_messages.len() == MAX_TRACKED_MESSAGES {
+            sent_messages.pop_front();
+        }
+        sent_messages.push_back(SentMessage {
+            id: message.id,
+            time: message.date,
+        });
+    }
+
+    /// Remembers `hash` as the command last run for the message and returns the one before.
+    pub fn swap_message_command(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        hash: u64,
+    ) -> Option<u64> {
+        let mut message_commands = self.message_commands.lock().unwrap();
+        let key = (chat_id, message_id);
+        let previous = message_commands
+            .iter()
+            .position(|(message, _)| *message == key)
+            .and_then(|i| message_commands.remove(i))
+            .map(|(_, hash)| hash);
+        if message_commands.len() == MAX_TRACKED_COMMANDS {
+            message_commands.pop_front();
+        }
+        message_commands.push_back((key, hash));
+        previous
+    }
+
+    /// Forgets and returns up to `count` of the bot's latest messages in the chat, newest first.
+    pub fn take_sent_messages(&self, chat_id: ChatId, count: usize) -> Vec<SentMessage> {
+        let mut sent_messages = self.sent_messages.lock().unwrap();
+        let Some(sent_messages) = sent_messages.get_mut(&chat_id) else {
+            return Vec::new();
+        };
+        let keep = sent_messages.len().saturating_sub(count);
+        sent_messages.drain(keep..).rev().collect()
+    }
+
+    /// Remembers a prompt for `/history`, dropping the user's oldest one when they have too many.
+    pub fn record_prompt(&self, user_id: UserId, prompt: &str) {
+        let mut prompts = self.prompts.lock().unwrap();
+        let prompts = prompts.entry(user_id).or_default();
+        if prompts.len() == MAX_RECORDED_PROMPTS {
+            prompts.pop_front();
+        }
+        prompts.push_back(PromptEntry {
+            time: Utc::now(),
+            prompt: prompt.to_string(),
+        });
+    }
+
+    /// The user's past prompts containing `query` (ignoring case), newest first.
+    pub fn search_prompts(&self, user_id: UserId, query: &str) -> Vec<PromptEntry> {
+        let query = query.to_lowercase();
+        self.prompts
+            .lock()
+            .unwrap()
+            .get(&user_id)
+            .map(|prompts| {
+                prompts
+                    .iter()
+                    .rev()
+                    .filter(|entry| entry.prompt.to_lowercase().contains(&query))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns a new id used to tie log lines and recorded errors to a single request.
+    pub fn next_request_id(&self) -> u64 {
+        self.last_request_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Remembers an error for `/errors`, dropping the oldest one when the buffer is full.
+    /// The returned cell adds the server's diagnostics to it, once they're known.
+    pub fn record_error(
+        &self,
+        request_id: u64,
+        message: impl Into<String>,
+    ) -> Arc<OnceLock<String>> {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == MAX_RECORDED_ERRORS {
+            errors.pop_front();
+        }
+        let diagnostics = Arc::default();
+        errors.push_back(ErrorEntry {
+            time: Utc::now(),
+            request_id,
+            message: message.into(),
+            diagnostics: Arc::clone(&diagnostics),
+        });
+        diagnostics
+    }
+
+    /// Recorded errors, oldest first.
+    pub fn recent_errors(&self) -> Vec<ErrorEntry> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use serde_json::json;
+use tracing::info;
+
+use crate::{config::Config, llm::client};
+
+// Speech is slow on small hardware, but a server that stopped answering shouldn't hold up /say
+const TTS_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Turns `text` into speech with the configured TTS server, which has to speak the OpenAI
+/// `/v1/audio/speech` API. Asks for Opus, the only format Telegram plays as a voice message.
+/// Returns `None` if TTS isn't configured.
+pub async fn synthesize(config: &Config, text: &str) -> Option<reqwest::Result<Vec<u8>>> {
+    let url = config.tts_url.as_deref()?;
+    let mut body = json!({
+        "model": config.tts_model,
+        "input": text,
+        "response_format": "opus",
+    });
+    if let Some(voice) = &config.tts_voice {
+        body["voice"] = json!(voice);
+    }
+
+    info!("Synthesizing {} characters of speech", text.chars().count());
+    let result = async {
+        let response = client()
+            .post(url)
+            .timeout(TTS_TIMEOUT)
+            .json(&body)
+            .send()
+            .await?;
+        Ok(response.error_for_status()?.bytes().await?.to_vec())
+    };
+    Some(result.await)
+}
@@ -0,0 +1,52 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use teloxide::{prelude::*, types::ChatAction};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn, Instrument};
+
+// Telegram shows the typing status for about 5 seconds, resend it a bit earlier so it doesn't flicker
+const TYPING_INTERVAL: Duration = Duration::from_secs(4);
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Keeps sending the typing indicator until it's dropped. Tying it to a guard instead of a flag
+/// means it also stops when a request fails or gets cancelled halfway, so the tasks can't pile up.
+pub struct TypingIndicator {
+    task: JoinHandle<()>,
+}
+
+impl TypingIndicator {
+    pub fn start(bot: &Bot, chat_id: ChatId) -> Self {
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        let bot = bot.clone();
+        let task = tokio::spawn(
+            async move {
+                loop {
+                    debug!("Sending typing indicator...");
+                    if let Err(e) = bot.send_chat_action(chat_id, ChatAction::Typing).await {
+                        warn!("Couldn't send the typing indicator: {}", e);
+                    }
+                    tokio::time::sleep(TYPING_INTERVAL).await;
+                }
+            }
+            .in_current_span(),
+        );
+        Self { task }
+    }
+
+    /// How many typing indicators are running right now, shown in `/metrics`.
+    pub fn active() -> usize {
+        ACTIVE.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for TypingIndicator {
+    fn drop(&mut self) {
+        info!("Stopping typing indicator");
+        self.task.abort();
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}